@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use log::warn;
+use ostd::{
+    mm::{DmaStream, DmaStreamSlice, VmIo},
+    sync::{LocalIrqDisabled, SpinLockGuard},
+    Pod,
+};
+
+use super::{
+    buffer::DmaSliceAllocator,
+    control::{VirtioGpuRect, VirtioGpuResourceFlush, VirtioGpuTransferToHost2D},
+    device::{check_response, trace_transfer, wait_for_tokens, Framebuffer, GPUDevice},
+    header::{VirtioGpuCtrlHdr, VirtioGpuCtrlType},
+};
+use crate::{device::VirtioDeviceError, queue::VirtQueue};
+
+/// A batch of control commands that are submitted with a single notification.
+///
+/// A batch is handed to the closure of [`GPUDevice::batch`]. The commands of
+/// the batch are queued without notifying the device, and when the closure
+/// returns, the device is notified once and all of them are waited for. If
+/// the batch outgrows the queue or the shared DMA buffers, the queued
+/// commands are completed early.
+///
+/// The control queue stays locked, with IRQs disabled, while the batch is
+/// alive, so the other control commands of the device must not be called
+/// meanwhile; see [`GPUDevice::batch`].
+pub struct ControlBatch<'a> {
+    device: &'a GPUDevice,
+    queue: SpinLockGuard<'a, VirtQueue, LocalIrqDisabled>,
+    request_buf: DmaSliceAllocator<'a>,
+    response_buf: DmaSliceAllocator<'a>,
+    /// The tokens and the response slices of the queued commands.
+    pending: Vec<(u16, DmaStreamSlice<&'a DmaStream>)>,
+    /// The first error of the queued or completed commands.
+    error: Option<VirtioDeviceError>,
+}
+
+impl<'a> ControlBatch<'a> {
+    pub(super) fn new(
+        device: &'a GPUDevice,
+        queue: SpinLockGuard<'a, VirtQueue, LocalIrqDisabled>,
+        request: &'a DmaStream,
+        response: &'a DmaStream,
+    ) -> Self {
+        Self {
+            device,
+            queue,
+            request_buf: DmaSliceAllocator::new(request),
+            response_buf: DmaSliceAllocator::new(response),
+            pending: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Queues a `VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D` command.
+    pub fn transfer_to_host_2d(&mut self, rect: VirtioGpuRect, offset: u64, resource_id: u32) {
+        let req = VirtioGpuTransferToHost2D::new(rect, offset, resource_id);
+        self.push(&req);
+    }
+
+    /// Queues a `VIRTIO_GPU_CMD_RESOURCE_FLUSH` command.
+    pub fn resource_flush(&mut self, rect: VirtioGpuRect, resource_id: u32) {
+        let req = VirtioGpuResourceFlush::new(rect, resource_id);
+        self.push(&req);
+    }
+
+    /// Queues the commands that make the `rect` area of the framebuffer visible.
+    ///
+    /// This is the batched version of [`GPUDevice::flush_rect`].
+    pub fn flush_rect(&mut self, rect: VirtioGpuRect) {
+        let Some(framebuffer) = self.device.current_framebuffer() else {
+            return;
+        };
+        self.flush_framebuffer(&framebuffer, rect, false);
+    }
+
+    /// Queues the commands that copy the `rect` area of a framebuffer to the
+    /// host and flush it.
+    ///
+    /// `draw_locked` tells whether the caller holds the draw lock of the
+    /// framebuffer.
+    pub(super) fn flush_framebuffer(
+        &mut self,
+        framebuffer: &Framebuffer,
+        rect: VirtioGpuRect,
+        draw_locked: bool,
+    ) {
+        let resource_id = framebuffer.resource_id();
+        let flush = self.device.prepare_flush(framebuffer, rect, draw_locked);
+        let rect = flush.rect();
+        if !framebuffer.is_blob() {
+            trace_transfer(resource_id, &rect, framebuffer.format());
+            self.transfer_to_host_2d(rect, flush.offset(), resource_id);
+        }
+        // The host must have copied the software cursor before it is erased.
+        if flush.has_cursor() {
+            self.complete();
+        }
+        flush.finish();
+        if framebuffer.is_offscreen() {
+            return;
+        }
+        self.resource_flush(rect, resource_id);
+
+        // Cursor commands go to the cursor queue, so they need not wait for the batch.
+        if let Err(err) = self.device.send_pending_cursor_move() {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    /// Submits the queued commands and waits for all of them.
+    ///
+    /// Returns the first error of the commands in the batch.
+    pub(super) fn end_batch(mut self) -> Result<(), VirtioDeviceError> {
+        self.complete();
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn push<Req: Pod>(&mut self, req: &Req) {
+        if let Err(err) = self.device.check_ready() {
+            self.error.get_or_insert(err);
+            return;
+        }
+
+        let resp_len = size_of::<VirtioGpuCtrlHdr>();
+        if self.queue.available_desc() < 2
+            || self.request_buf.remaining() < size_of::<Req>()
+            || self.response_buf.remaining() < resp_len
+        {
+            self.complete();
+        }
+
+        let req_slice = self.request_buf.alloc_val(req);
+        let resp_slice = self.response_buf.alloc_val(&VirtioGpuCtrlHdr::new_zeroed());
+        match self.queue.add_dma_buf(&[&req_slice], &[&resp_slice]) {
+            Ok(token) => {
+                // The commands of a batch are waited for together, so their
+                // time is not counted.
+                let cmd_type: u32 = req_slice.read_val(0).unwrap();
+                self.device.record_command(cmd_type, 0);
+                #[cfg(ktest)]
+                self.device.log_request(&req_slice);
+                self.pending.push((token, resp_slice));
+            }
+            Err(err) => {
+                self.error.get_or_insert(err.into());
+            }
+        }
+    }
+
+    /// Notifies the device of the queued commands and waits for all of them.
+    fn complete(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        if self.queue.should_notify() {
+            self.queue.notify();
+        }
+        let tokens: Vec<u16> = self.pending.iter().map(|(token, _)| *token).collect();
+        wait_for_tokens(&mut self.queue, &tokens);
+
+        for (_, resp_slice) in self.pending.drain(..) {
+            resp_slice.sync().unwrap();
+            let resp: VirtioGpuCtrlHdr = resp_slice.read_val(0).unwrap();
+            if let Err(err) = check_response(&resp, VirtioGpuCtrlType::RespOkNodata) {
+                self.error.get_or_insert(err);
+            }
+        }
+
+        // All slices have been handed back by the device, so the buffers can be reused.
+        self.request_buf.reset();
+        self.response_buf.reset();
+    }
+}
+
+impl Drop for ControlBatch<'_> {
+    fn drop(&mut self) {
+        self.complete();
+        if let Some(err) = self.error.take() {
+            warn!("control batch dropped with an error: {:?}", err);
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::gpu::{control::VirtioGpuFormat, test_device};
+
+    #[ktest]
+    fn full_queue_completes_the_batch_early() {
+        let Some(device) = test_device() else {
+            return;
+        };
+        let resource_id = device.alloc_resource_id();
+        device
+            .resource_create_2d(resource_id, VirtioGpuFormat::B8G8R8A8Unorm, 16, 16)
+            .unwrap();
+        let rect = VirtioGpuRect::new(0, 0, 16, 16);
+
+        let result = device.batch(|batch| {
+            // Each command takes two descriptors, so the queue fits half as
+            // many commands.
+            let fits = batch.queue.available_desc() / 2;
+            for _ in 0..fits {
+                batch.resource_flush(rect, resource_id);
+            }
+            assert_eq!(batch.pending.len(), fits);
+            assert!(batch.queue.available_desc() < 2);
+
+            // The queued commands are completed to make room for the next one.
+            batch.resource_flush(rect, resource_id);
+            assert_eq!(batch.pending.len(), 1);
+            assert!(batch.error.is_none());
+            Ok(())
+        });
+        device.resource_unref(resource_id).unwrap();
+        result.unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::mem::{size_of, size_of_val};
+
+use ostd::{
+    mm::{DmaDirection, DmaStream, DmaStreamSlice, FrameAllocOptions, VmIo, PAGE_SIZE},
+    Pod,
+};
+
+use crate::device::VirtioDeviceError;
+
+/// Allocates a DMA buffer of at least `nbytes` bytes.
+///
+/// Fails with [`VirtioDeviceError::GuestOutOfMemory`] if the frames cannot be
+/// allocated or mapped for DMA.
+pub(super) fn alloc_dma_stream(
+    nbytes: usize,
+    direction: DmaDirection,
+) -> Result<DmaStream, VirtioDeviceError> {
+    let segment = FrameAllocOptions::new()
+        .alloc_segment(nbytes.div_ceil(PAGE_SIZE))
+        .map_err(|_| VirtioDeviceError::GuestOutOfMemory)?;
+    DmaStream::map(segment.into(), direction, false)
+        .map_err(|_| VirtioDeviceError::GuestOutOfMemory)
+}
+
+/// Hands out consecutive slices of a DMA buffer that is shared by the parts of a command.
+///
+/// The slices never overlap, since each one starts at the high-water mark
+/// left by the previous ones.
+pub(super) struct DmaSliceAllocator<'a> {
+    stream: &'a DmaStream,
+    used: usize,
+}
+
+impl<'a> DmaSliceAllocator<'a> {
+    pub(super) fn new(stream: &'a DmaStream) -> Self {
+        Self { stream, used: 0 }
+    }
+
+    /// Allocates a slice holding `val`, which is written and synced to the device.
+    pub(super) fn alloc_val<T: Pod>(&mut self, val: &T) -> DmaStreamSlice<&'a DmaStream> {
+        let slice = self.alloc(size_of::<T>());
+        slice.write_val(0, val).unwrap();
+        slice.sync().unwrap();
+        slice
+    }
+
+    /// Allocates a slice holding `vals`, which are written and synced to the device.
+    pub(super) fn alloc_slice<T: Pod>(&mut self, vals: &[T]) -> DmaStreamSlice<&'a DmaStream> {
+        let slice = self.alloc(size_of_val(vals));
+        slice.write_slice(0, vals).unwrap();
+        slice.sync().unwrap();
+        slice
+    }
+
+    /// Returns the number of bytes that are not allocated yet.
+    pub(super) fn remaining(&self) -> usize {
+        self.stream.nbytes() - self.used
+    }
+
+    /// Makes the whole buffer available again.
+    ///
+    /// The slices allocated before must no longer be in use by the device.
+    pub(super) fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    fn alloc(&mut self, len: usize) -> DmaStreamSlice<&'a DmaStream> {
+        debug_assert!(len > 0, "empty DMA slice");
+        debug_assert!(
+            self.used + len <= self.stream.nbytes(),
+            "DMA slice [{}, {}) overruns the buffer of {} bytes",
+            self.used,
+            self.used + len,
+            self.stream.nbytes()
+        );
+
+        let slice = DmaStreamSlice::new(self.stream, self.used, len);
+        self.used += len;
+        slice
+    }
+}
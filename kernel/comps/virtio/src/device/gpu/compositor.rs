@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! A compositor that stacks 2D resources on a framebuffer.
+//!
+//! Each layer shows a resource created by the driver, such as a background,
+//! a console or a cursor, in an area of the framebuffer. The layers are
+//! blended from the bottom up by the CPU, from the guest backing storage of
+//! their resources, so the device only sees the resulting framebuffer.
+
+use alloc::{vec, vec::Vec};
+
+use ostd::mm::{DmaStream, VmIo};
+
+use super::{
+    control::VirtioGpuRect,
+    device::FramebufferResource,
+    draw::{clip_rect, pixel_offset, PixelFormat},
+    software_cursor::union_rect,
+};
+use crate::device::VirtioDeviceError;
+
+/// A resource shown in an area of the framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layer {
+    /// The resource, which must have guest backing storage and the format of
+    /// the framebuffer.
+    pub resource_id: u32,
+    /// The area of the framebuffer where the top-left part of the resource is
+    /// shown.
+    pub rect: VirtioGpuRect,
+    /// The opacity of the layer, from 0 (transparent) to 255 (opaque).
+    ///
+    /// It is combined with the alpha of the pixels if the format has one.
+    pub alpha: u8,
+}
+
+/// An ordered stack of layers, from the bottom to the top.
+#[derive(Debug, Default)]
+pub struct Compositor {
+    layers: Vec<Layer>,
+}
+
+impl Compositor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts a layer on top of the others.
+    pub fn push(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
+
+    /// Removes the layer at `index`, counted from the bottom.
+    ///
+    /// Returns `None` if there is no such layer.
+    pub fn remove(&mut self, index: usize) -> Option<Layer> {
+        (index < self.layers.len()).then(|| self.layers.remove(index))
+    }
+
+    /// Returns the layers, from the bottom to the top, e.g., to move them.
+    pub fn layers_mut(&mut self) -> &mut [Layer] {
+        &mut self.layers
+    }
+
+    /// Blends the layers into the framebuffer and flushes the areas that they
+    /// cover.
+    ///
+    /// The pixels not covered by any layer are left as they are. Fails with
+    /// [`VirtioDeviceError::InvalidResource`] if the resource of a layer does
+    /// not exist, has no guest backing storage or has another format than
+    /// the framebuffer, and with [`VirtioDeviceError::InvalidRect`] if it is
+    /// smaller than its area. Nothing is drawn in that case.
+    pub fn composite_and_present(
+        &self,
+        framebuffer: &FramebufferResource,
+    ) -> Result<(), VirtioDeviceError> {
+        let Some(device) = framebuffer.device() else {
+            return Ok(());
+        };
+
+        let mut sources = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            let info = device
+                .resource_info(layer.resource_id)
+                .ok_or(VirtioDeviceError::InvalidResource(layer.resource_id))?;
+            if PixelFormat::from(info.format()) != framebuffer.format() {
+                return Err(VirtioDeviceError::InvalidResource(layer.resource_id));
+            }
+            let size = VirtioGpuRect::new(0, 0, layer.rect.width(), layer.rect.height());
+            if !info.contains(&size) {
+                return Err(VirtioDeviceError::InvalidRect);
+            }
+            let stride = info.stride();
+            let backing = info
+                .backing()
+                .ok_or(VirtioDeviceError::InvalidResource(layer.resource_id))?
+                .clone();
+            sources.push((layer, backing, stride));
+        }
+
+        let format = framebuffer.format();
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let guard = framebuffer.lock();
+        let mut damage = None;
+        for (layer, backing, stride) in &sources {
+            let Some(area) = clip_rect(&layer.rect, &guard.rect()) else {
+                continue;
+            };
+            let row_len = area.width() as usize * bytes_per_pixel;
+            let (mut src_row, mut dst_row) = (vec![0u8; row_len], vec![0u8; row_len]);
+            for y in area.y()..area.y() + area.height() {
+                let (src_x, src_y) = (area.x() - layer.rect.x(), y - layer.rect.y());
+                let src_offset = pixel_offset(src_x, src_y, *stride, bytes_per_pixel)
+                    .expect("the layer is within its resource");
+                read_row(backing, src_offset, &mut src_row);
+                if layer.alpha == u8::MAX && !format.has_alpha() {
+                    guard.write_row(area.x(), y, &src_row);
+                    continue;
+                }
+                guard.read_row(area.x(), y, &mut dst_row);
+                blend_row(&mut dst_row, &src_row, layer.alpha, format);
+                guard.write_row(area.x(), y, &dst_row);
+            }
+            damage = Some(match damage {
+                Some(damage) => union_rect(&damage, &area),
+                None => area,
+            });
+        }
+
+        match damage {
+            Some(damage) => guard.flush_rect(damage),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_row(backing: &DmaStream, offset: usize, row: &mut [u8]) {
+    backing
+        .read_bytes(offset, row)
+        .expect("error reading a layer");
+}
+
+/// Blends a row of source pixels over a row of destination pixels in
+/// `format`, with the opacity of the layer, keeping the alpha or padding of
+/// the destination.
+fn blend_row(dst: &mut [u8], src: &[u8], alpha: u8, format: PixelFormat) {
+    let alpha_index = format.alpha_index();
+    let bytes_per_pixel = format.bytes_per_pixel();
+    for (dst, src) in dst
+        .chunks_exact_mut(bytes_per_pixel)
+        .zip(src.chunks_exact(bytes_per_pixel))
+    {
+        let pixel_alpha = if format.has_alpha() {
+            src[alpha_index] as u32
+        } else {
+            255
+        };
+        let alpha = (alpha as u32 * pixel_alpha + 127) / 255;
+        for channel in (0..bytes_per_pixel).filter(|&channel| channel != alpha_index) {
+            let value = src[channel] as u32 * alpha + dst[channel] as u32 * (255 - alpha);
+            dst[channel] = ((value + 127) / 255) as u8;
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn layers_blend_with_their_alpha() {
+        let format = PixelFormat::Bgra8888;
+        let mut dst = [0, 0, 0, 0xff, 200, 200, 200, 0xff];
+        // The first pixel is opaque, and the second one is half transparent.
+        let src = [100, 100, 100, 0xff, 100, 100, 100, 0x80];
+        blend_row(&mut dst, &src, 0xff, format);
+        assert_eq!(dst, [100, 100, 100, 0xff, 150, 150, 150, 0xff]);
+
+        let mut dst = [0, 0, 0, 0xff];
+        blend_row(&mut dst, &[200, 200, 200, 0], 0x80, PixelFormat::Bgrx8888);
+        assert_eq!(dst, [100, 100, 100, 0xff]);
+
+        let mut dst = [10, 20, 30, 0xff];
+        blend_row(&mut dst, &[200, 200, 200, 0xff], 0, format);
+        assert_eq!(dst, [10, 20, 30, 0xff]);
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::mem::offset_of;
+
+use aster_util::safe_ptr::SafePtr;
+use bitflags::bitflags;
+use ostd::Pod;
+
+use crate::transport::{ConfigManager, VirtioTransport};
+
+bitflags! {
+    /// Virtio GPU feature bits.
+    pub struct GPUFeatures: u64 {
+        /// Virgl 3D mode is supported.
+        const VIRTIO_GPU_F_VIRGL = 1 << 0;
+        /// EDID is supported.
+        const VIRTIO_GPU_F_EDID = 1 << 1;
+        /// Assigning UUIDs to resources is supported.
+        const VIRTIO_GPU_F_RESOURCE_UUID = 1 << 2;
+        /// Blob resources are supported.
+        const VIRTIO_GPU_F_RESOURCE_BLOB = 1 << 3;
+        /// Multiple context types and synchronization timelines are supported.
+        const VIRTIO_GPU_F_CONTEXT_INIT = 1 << 4;
+    }
+}
+
+impl GPUFeatures {
+    /// The features that the driver knows how to use.
+    pub(super) const SUPPORTED: Self = Self::from_bits_truncate(
+        Self::VIRTIO_GPU_F_VIRGL.bits()
+            | Self::VIRTIO_GPU_F_EDID.bits()
+            | Self::VIRTIO_GPU_F_RESOURCE_UUID.bits()
+            | Self::VIRTIO_GPU_F_RESOURCE_BLOB.bits()
+            | Self::VIRTIO_GPU_F_CONTEXT_INIT.bits(),
+    );
+}
+
+/// The event in `events_read` that signals a change of the display configuration.
+pub const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, Pod)]
+#[repr(C)]
+pub struct VirtioGPUConfig {
+    /// Signals pending events to the driver. The driver must not write to this field.
+    pub events_read: u32,
+    /// Clears pending events in the device. Writing a '1' into a bit will clear
+    /// the corresponding bit in `events_read`, mimicking write-to-clear behavior.
+    pub events_clear: u32,
+    /// The maximum number of scanouts supported by the device. Minimum value is 1,
+    /// maximum value is 16.
+    pub num_scanouts: u32,
+    /// The maximum number of capability sets supported by the device.
+    pub num_capsets: u32,
+}
+
+impl VirtioGPUConfig {
+    pub(super) fn new_manager(transport: &dyn VirtioTransport) -> ConfigManager<Self> {
+        let safe_ptr = transport
+            .device_config_mem()
+            .map(|mem| SafePtr::new(mem, 0));
+        let bar_space = transport.device_config_bar();
+        ConfigManager::new(safe_ptr, bar_space)
+    }
+}
+
+impl ConfigManager<VirtioGPUConfig> {
+    pub(super) fn read_config(&self) -> VirtioGPUConfig {
+        let mut gpu_config = VirtioGPUConfig::new_uninit();
+        gpu_config.events_read = self
+            .read_once::<u32>(offset_of!(VirtioGPUConfig, events_read))
+            .unwrap();
+        gpu_config.events_clear = self
+            .read_once::<u32>(offset_of!(VirtioGPUConfig, events_clear))
+            .unwrap();
+        gpu_config.num_scanouts = self
+            .read_once::<u32>(offset_of!(VirtioGPUConfig, num_scanouts))
+            .unwrap();
+        gpu_config.num_capsets = self
+            .read_once::<u32>(offset_of!(VirtioGPUConfig, num_capsets))
+            .unwrap();
+
+        gpu_config
+    }
+
+    /// Reads the pending events from `events_read`.
+    pub(super) fn read_events(&self) -> u32 {
+        self.read_once::<u32>(offset_of!(VirtioGPUConfig, events_read))
+            .unwrap()
+    }
+
+    /// Clears the `events` in `events_read` by writing them to `events_clear`.
+    pub(super) fn clear_events(&self, events: u32) {
+        self.write_once(offset_of!(VirtioGPUConfig, events_clear), events)
+            .unwrap();
+    }
+}
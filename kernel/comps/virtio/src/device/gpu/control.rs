@@ -0,0 +1,1034 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::{fmt, mem::offset_of};
+
+use int_to_c_enum::TryFromInt;
+use ostd::Pod;
+
+use super::header::{VirtioGpuCtrlHdr, VirtioGpuCtrlType};
+use crate::device::VirtioDeviceError;
+
+/// The maximum number of scanouts that a device may have.
+pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+/// A rectangle in a scanout or in a resource.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod)]
+pub struct VirtioGpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl VirtioGpuRect {
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Creates a rectangle that must lie within `bounds`.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidRect`] if the rectangle is empty
+    /// or exceeds `bounds`, e.g., the size of a display or a resource.
+    pub fn try_new(
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        bounds: &VirtioGpuRect,
+    ) -> Result<Self, VirtioDeviceError> {
+        let rect = Self::new(x, y, width, height);
+        if width == 0 || height == 0 || !rect.is_within(bounds) {
+            return Err(VirtioDeviceError::InvalidRect);
+        }
+        Ok(rect)
+    }
+
+    /// Returns whether the rectangle lies within `bounds`.
+    pub fn is_within(&self, bounds: &VirtioGpuRect) -> bool {
+        let end = |start: u32, len: u32| start as u64 + len as u64;
+        self.x >= bounds.x
+            && self.y >= bounds.y
+            && end(self.x, self.width) <= end(bounds.x, bounds.width)
+            && end(self.y, self.height) <= end(bounds.y, bounds.height)
+    }
+
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// Formats the rectangle as `WxH+X+Y`, like X11 geometries.
+impl fmt::Display for VirtioGpuRect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+/// The information of one scanout.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod)]
+pub struct VirtioGpuDisplayOne {
+    /// The preferred position and size of the scanout.
+    pub r: VirtioGpuRect,
+    /// Whether the scanout is enabled by the user.
+    pub enabled: u32,
+    pub flags: u32,
+}
+
+/// The response of `VIRTIO_GPU_CMD_GET_DISPLAY_INFO`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod)]
+pub struct VirtioGpuRespDisplayInfo {
+    pub hdr: VirtioGpuCtrlHdr,
+    pub pmodes: [VirtioGpuDisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+impl VirtioGpuRespDisplayInfo {
+    /// Returns the preferred rectangle of the scanout.
+    pub fn get_rect(&self, index: usize) -> Option<VirtioGpuRect> {
+        self.pmodes.get(index).map(|pmode| pmode.r)
+    }
+
+    /// Returns whether the scanout is enabled.
+    pub fn is_enabled(&self, index: usize) -> bool {
+        self.pmodes
+            .get(index)
+            .is_some_and(|pmode| pmode.enabled != 0)
+    }
+}
+
+/// Lists the enabled scanouts with their preferred rectangles,
+/// e.g., `scanout 0: 1280x800+0+0, scanout 1: 1024x768+1280+0`.
+impl fmt::Display for VirtioGpuRespDisplayInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut enabled = self
+            .pmodes
+            .iter()
+            .enumerate()
+            .filter(|(_, pmode)| pmode.enabled != 0)
+            .peekable();
+        if enabled.peek().is_none() {
+            return write!(f, "no enabled scanouts");
+        }
+
+        for (index, (scanout_id, pmode)) in enabled.enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "scanout {}: {}", scanout_id, pmode.r)?;
+        }
+        Ok(())
+    }
+}
+
+/// The maximum size of the EDID blob in a [`VirtioGpuRespEdid`].
+pub const VIRTIO_GPU_MAX_EDID_SIZE: usize = 1024;
+
+/// The request of `VIRTIO_GPU_CMD_GET_CAPSET_INFO`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuGetCapsetInfo {
+    hdr: VirtioGpuCtrlHdr,
+    capset_index: u32,
+    padding: u32,
+}
+
+impl VirtioGpuGetCapsetInfo {
+    pub fn new(capset_index: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdGetCapsetInfo),
+            capset_index,
+            padding: 0,
+        }
+    }
+}
+
+/// The response of `VIRTIO_GPU_CMD_GET_CAPSET_INFO`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuRespCapsetInfo {
+    pub hdr: VirtioGpuCtrlHdr,
+    pub capset_id: u32,
+    pub capset_max_version: u32,
+    pub capset_max_size: u32,
+    padding: u32,
+}
+
+/// The request of `VIRTIO_GPU_CMD_GET_EDID`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuGetEdid {
+    hdr: VirtioGpuCtrlHdr,
+    scanout: u32,
+    padding: u32,
+}
+
+impl VirtioGpuGetEdid {
+    pub fn new(scanout: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdGetEdid),
+            scanout,
+            padding: 0,
+        }
+    }
+}
+
+/// The response of `VIRTIO_GPU_CMD_GET_EDID`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuRespEdid {
+    pub hdr: VirtioGpuCtrlHdr,
+    pub size: u32,
+    pub padding: u32,
+    pub edid: [u8; VIRTIO_GPU_MAX_EDID_SIZE],
+}
+
+impl VirtioGpuRespEdid {
+    /// Returns the valid bytes of the EDID blob.
+    pub fn edid(&self) -> &[u8] {
+        let size = (self.size as usize).min(VIRTIO_GPU_MAX_EDID_SIZE);
+        &self.edid[..size]
+    }
+}
+
+impl SizedResponse for VirtioGpuRespEdid {
+    const DATA_OFFSET: usize = offset_of!(VirtioGpuRespEdid, edid);
+
+    fn data_size(&self) -> usize {
+        self.size as usize
+    }
+}
+
+/// A response that ends with a blob, of which the device only writes as many
+/// bytes as it reports.
+///
+/// Only the fields before the blob and the reported bytes of the blob need to
+/// be synced from the device, which saves cache maintenance on platforms where
+/// DMA is not coherent.
+pub trait SizedResponse: Pod {
+    /// The offset of the blob, which follows all other fields.
+    const DATA_OFFSET: usize;
+
+    /// Returns the number of bytes of the blob that the device reports.
+    ///
+    /// This is only called with the fields before the blob synced.
+    fn data_size(&self) -> usize;
+}
+
+/// The pixel formats of 2D resources.
+///
+/// The name of a format gives the order of its components in memory,
+/// e.g., `B8G8R8A8Unorm` stores blue in the byte with the lowest address.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromInt)]
+pub enum VirtioGpuFormat {
+    B8G8R8A8Unorm = 1,
+    B8G8R8X8Unorm = 2,
+    A8R8G8B8Unorm = 3,
+    X8R8G8B8Unorm = 4,
+    R8G8B8A8Unorm = 67,
+    X8B8G8R8Unorm = 68,
+    A8B8G8R8Unorm = 121,
+    R8G8B8X8Unorm = 134,
+}
+
+impl VirtioGpuFormat {
+    /// All formats defined by the virtio-gpu specification.
+    pub const ALL: [Self; 8] = [
+        Self::B8G8R8A8Unorm,
+        Self::B8G8R8X8Unorm,
+        Self::A8R8G8B8Unorm,
+        Self::X8R8G8B8Unorm,
+        Self::R8G8B8A8Unorm,
+        Self::X8B8G8R8Unorm,
+        Self::A8B8G8R8Unorm,
+        Self::R8G8B8X8Unorm,
+    ];
+
+    /// Returns the number of bytes of a pixel in the format.
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::B8G8R8A8Unorm
+            | Self::B8G8R8X8Unorm
+            | Self::A8R8G8B8Unorm
+            | Self::X8R8G8B8Unorm
+            | Self::R8G8B8A8Unorm
+            | Self::X8B8G8R8Unorm
+            | Self::A8B8G8R8Unorm
+            | Self::R8G8B8X8Unorm => 4,
+        }
+    }
+
+    /// Returns whether the pixels of the format carry an alpha component.
+    pub const fn has_alpha(self) -> bool {
+        match self {
+            Self::B8G8R8A8Unorm
+            | Self::A8R8G8B8Unorm
+            | Self::R8G8B8A8Unorm
+            | Self::A8B8G8R8Unorm => true,
+            Self::B8G8R8X8Unorm
+            | Self::X8R8G8B8Unorm
+            | Self::X8B8G8R8Unorm
+            | Self::R8G8B8X8Unorm => false,
+        }
+    }
+
+    /// Converts a pixel in the format to the `R8G8B8A8` order.
+    ///
+    /// The padding component of the formats without alpha becomes an opaque alpha.
+    pub fn to_rgba(self, pixel: [u8; 4]) -> [u8; 4] {
+        let [p0, p1, p2, p3] = pixel;
+        match self {
+            Self::B8G8R8A8Unorm => [p2, p1, p0, p3],
+            Self::B8G8R8X8Unorm => [p2, p1, p0, 0xff],
+            Self::A8R8G8B8Unorm => [p1, p2, p3, p0],
+            Self::X8R8G8B8Unorm => [p1, p2, p3, 0xff],
+            Self::R8G8B8A8Unorm => pixel,
+            Self::X8B8G8R8Unorm => [p3, p2, p1, 0xff],
+            Self::A8B8G8R8Unorm => [p3, p2, p1, p0],
+            Self::R8G8B8X8Unorm => [p0, p1, p2, 0xff],
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_CREATE_2D`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceCreate2D {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+impl VirtioGpuResourceCreate2D {
+    pub fn new(resource_id: u32, format: VirtioGpuFormat, width: u32, height: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceCreate2d),
+            resource_id,
+            format: format as u32,
+            width,
+            height,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_UNREF`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceUnref {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuResourceUnref {
+    pub fn new(resource_id: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceUnref),
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_SET_SCANOUT`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuSetScanout {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+impl VirtioGpuSetScanout {
+    pub fn new(scanout_id: u32, resource_id: u32, r: VirtioGpuRect) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdSetScanout),
+            r,
+            scanout_id,
+            resource_id,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_FLUSH`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceFlush {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuResourceFlush {
+    pub fn new(r: VirtioGpuRect, resource_id: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceFlush),
+            r,
+            resource_id,
+            padding: 0,
+        }
+    }
+
+    /// Makes the request a fenced one.
+    pub fn with_fence(mut self, fence_id: u64) -> Self {
+        self.hdr = self.hdr.with_fence(fence_id);
+        self
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuTransferToHost2D {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuTransferToHost2D {
+    pub fn new(r: VirtioGpuRect, offset: u64, resource_id: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdTransferToHost2d),
+            r,
+            offset,
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING`.
+///
+/// The request is followed by `nr_entries` [`VirtioGpuMemEntry`]s.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceAttachBacking {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+impl VirtioGpuResourceAttachBacking {
+    pub fn new(resource_id: u32, nr_entries: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceAttachBacking),
+            resource_id,
+            nr_entries,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceDetachBacking {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuResourceDetachBacking {
+    pub fn new(resource_id: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceDetachBacking),
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+/// A guest memory region used as (a part of) the backing storage of a resource.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuMemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+impl VirtioGpuMemEntry {
+    pub fn new(addr: u64, length: u32) -> Self {
+        Self {
+            addr,
+            length,
+            padding: 0,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceAssignUuid {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuResourceAssignUuid {
+    pub fn new(resource_id: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceAssignUuid),
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+/// The response of `VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod)]
+pub struct VirtioGpuRespResourceUuid {
+    pub hdr: VirtioGpuCtrlHdr,
+    pub uuid: [u8; 16],
+}
+
+/// The blob memory is guest memory given by the [`VirtioGpuMemEntry`]s of the request.
+pub const VIRTIO_GPU_BLOB_MEM_GUEST: u32 = 0x0001;
+/// The blob memory is host memory of a 3D context.
+pub const VIRTIO_GPU_BLOB_MEM_HOST3D: u32 = 0x0002;
+/// The blob memory is guest memory that is also used by a 3D context.
+pub const VIRTIO_GPU_BLOB_MEM_HOST3D_GUEST: u32 = 0x0003;
+
+/// The blob can be mapped into the guest.
+pub const VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE: u32 = 0x0001;
+/// The blob can be shared with other virtio devices.
+pub const VIRTIO_GPU_BLOB_FLAG_USE_SHAREABLE: u32 = 0x0002;
+/// The blob can be shared across devices.
+pub const VIRTIO_GPU_BLOB_FLAG_USE_CROSS_DEVICE: u32 = 0x0004;
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB`.
+///
+/// The request is followed by `nr_entries` [`VirtioGpuMemEntry`]s.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceCreateBlob {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    blob_mem: u32,
+    blob_flags: u32,
+    nr_entries: u32,
+    blob_id: u64,
+    size: u64,
+}
+
+impl VirtioGpuResourceCreateBlob {
+    pub fn new(
+        resource_id: u32,
+        blob_mem: u32,
+        blob_flags: u32,
+        nr_entries: u32,
+        blob_id: u64,
+        size: u64,
+    ) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceCreateBlob),
+            resource_id,
+            blob_mem,
+            blob_flags,
+            nr_entries,
+            blob_id,
+            size,
+        }
+    }
+
+    /// Sets the context that owns the blob, which is required for
+    /// [`VIRTIO_GPU_BLOB_MEM_HOST3D`] and [`VIRTIO_GPU_BLOB_MEM_HOST3D_GUEST`] blobs.
+    pub fn with_ctx_id(mut self, ctx_id: u32) -> Self {
+        self.hdr = self.hdr.with_ctx_id(ctx_id);
+        self
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_SET_SCANOUT_BLOB`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuSetScanoutBlob {
+    hdr: VirtioGpuCtrlHdr,
+    r: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    padding: u32,
+    strides: [u32; 4],
+    offsets: [u32; 4],
+}
+
+impl VirtioGpuSetScanoutBlob {
+    /// Creates a request that displays a single-plane image in a blob.
+    ///
+    /// The image of `width * height` pixels in `format` starts at `offset`
+    /// of the blob, and its rows are `stride` bytes apart.
+    #[expect(clippy::too_many_arguments)]
+    pub fn new(
+        scanout_id: u32,
+        resource_id: u32,
+        r: VirtioGpuRect,
+        width: u32,
+        height: u32,
+        format: VirtioGpuFormat,
+        stride: u32,
+        offset: u32,
+    ) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdSetScanoutBlob),
+            r,
+            scanout_id,
+            resource_id,
+            width,
+            height,
+            format: format as u32,
+            padding: 0,
+            strides: [stride, 0, 0, 0],
+            offsets: [offset, 0, 0, 0],
+        }
+    }
+}
+
+/// The id of the shared memory region where blobs of host memory are mapped.
+pub const VIRTIO_GPU_SHM_ID_HOST_VISIBLE: u8 = 1;
+
+/// The mask of the caching type in the `map_info` of a mapped blob.
+pub const VIRTIO_GPU_MAP_CACHE_MASK: u32 = 0x0f;
+pub const VIRTIO_GPU_MAP_CACHE_NONE: u32 = 0x00;
+pub const VIRTIO_GPU_MAP_CACHE_CACHED: u32 = 0x01;
+pub const VIRTIO_GPU_MAP_CACHE_UNCACHED: u32 = 0x02;
+pub const VIRTIO_GPU_MAP_CACHE_WC: u32 = 0x03;
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceMapBlob {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+    /// The offset in the host-visible region where the blob is mapped.
+    offset: u64,
+}
+
+impl VirtioGpuResourceMapBlob {
+    pub fn new(resource_id: u32, offset: u64) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceMapBlob),
+            resource_id,
+            padding: 0,
+            offset,
+        }
+    }
+}
+
+/// The response of `VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuRespMapInfo {
+    pub hdr: VirtioGpuCtrlHdr,
+    /// The caching type of the mapping, in the [`VIRTIO_GPU_MAP_CACHE_MASK`] bits.
+    pub map_info: u32,
+    padding: u32,
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceUnmapBlob {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuResourceUnmapBlob {
+    pub fn new(resource_id: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceUnmapBlob),
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+/// A 3D box in a resource.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Pod)]
+pub struct VirtioGpuBox {
+    x: u32,
+    y: u32,
+    z: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+}
+
+impl VirtioGpuBox {
+    pub const fn new(x: u32, y: u32, z: u32, width: u32, height: u32, depth: u32) -> Self {
+        Self {
+            x,
+            y,
+            z,
+            width,
+            height,
+            depth,
+        }
+    }
+}
+
+/// The parameters of a 3D resource, see [`GPUDevice::resource_create_3d`].
+///
+/// The values are those of the Gallium `pipe_resource` that virgl creates on
+/// the host, e.g., `target` is a `pipe_texture_target` and `format` is a
+/// `virgl_formats`.
+///
+/// [`GPUDevice::resource_create_3d`]: super::device::GPUDevice::resource_create_3d
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Resource3dParams {
+    /// The id of the resource to create.
+    pub resource_id: u32,
+    /// The kind of the resource, e.g., a buffer or a 2D texture.
+    pub target: u32,
+    /// The format of the texels.
+    pub format: u32,
+    /// How the resource is bound to the pipeline, e.g., as a render target.
+    pub bind: u32,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    /// The number of layers of an array texture.
+    pub array_size: u32,
+    /// The index of the last mipmap level.
+    pub last_level: u32,
+    /// The number of samples per texel of a multisampled texture.
+    pub nr_samples: u32,
+    pub flags: u32,
+}
+
+/// The request of `VIRTIO_GPU_CMD_RESOURCE_CREATE_3D`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuResourceCreate3D {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    target: u32,
+    format: u32,
+    bind: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+    array_size: u32,
+    last_level: u32,
+    nr_samples: u32,
+    flags: u32,
+    padding: u32,
+}
+
+impl VirtioGpuResourceCreate3D {
+    pub fn new(params: &Resource3dParams) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdResourceCreate3d),
+            resource_id: params.resource_id,
+            target: params.target,
+            format: params.format,
+            bind: params.bind,
+            width: params.width,
+            height: params.height,
+            depth: params.depth,
+            array_size: params.array_size,
+            last_level: params.last_level,
+            nr_samples: params.nr_samples,
+            flags: params.flags,
+            padding: 0,
+        }
+    }
+}
+
+/// The area of a 3D resource that is copied by [`GPUDevice::transfer_to_host_3d`]
+/// or [`GPUDevice::transfer_from_host_3d`].
+///
+/// [`GPUDevice::transfer_to_host_3d`]: super::device::GPUDevice::transfer_to_host_3d
+/// [`GPUDevice::transfer_from_host_3d`]: super::device::GPUDevice::transfer_from_host_3d
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transfer3dParams {
+    /// The id of the resource.
+    pub resource_id: u32,
+    /// The texels to copy.
+    pub box_: VirtioGpuBox,
+    /// The offset of the first texel of the box in the backing storage.
+    pub offset: u64,
+    /// The mipmap level to copy.
+    pub level: u32,
+    /// The number of bytes between the rows in the backing storage, or zero
+    /// for the natural stride of the resource.
+    pub stride: u32,
+    /// The number of bytes between the layers (or depth slices) in the backing
+    /// storage, or zero for the natural one of the resource.
+    pub layer_stride: u32,
+}
+
+/// The request of `VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D` and `VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuTransferHost3D {
+    hdr: VirtioGpuCtrlHdr,
+    box_: VirtioGpuBox,
+    offset: u64,
+    resource_id: u32,
+    level: u32,
+    stride: u32,
+    layer_stride: u32,
+}
+
+impl VirtioGpuTransferHost3D {
+    /// Creates a request that copies `box_` from the host to the backing storage.
+    ///
+    /// A zero `stride` or `layer_stride` lets the host use the natural one of the resource.
+    pub fn new_from_host(
+        box_: VirtioGpuBox,
+        offset: u64,
+        resource_id: u32,
+        level: u32,
+        stride: u32,
+        layer_stride: u32,
+    ) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdTransferFromHost3d),
+            box_,
+            offset,
+            resource_id,
+            level,
+            stride,
+            layer_stride,
+        }
+    }
+
+    /// Creates a request that copies the area of `params` between the host
+    /// and the backing storage, in the direction of `type_`.
+    pub fn new(type_: VirtioGpuCtrlType, params: &Transfer3dParams) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(type_),
+            box_: params.box_,
+            offset: params.offset,
+            resource_id: params.resource_id,
+            level: params.level,
+            stride: params.stride,
+            layer_stride: params.layer_stride,
+        }
+    }
+
+    /// Sets the rendering context in which the host performs the transfer.
+    pub fn with_ctx_id(mut self, ctx_id: u32) -> Self {
+        self.hdr = self.hdr.with_ctx_id(ctx_id);
+        self
+    }
+}
+
+/// The maximum length of the debug name of a rendering context.
+pub const VIRTIO_GPU_CONTEXT_NAME_LEN: usize = 64;
+
+/// The bits of the `context_init` field of `VIRTIO_GPU_CMD_CTX_CREATE` that
+/// hold the capset id of the context type.
+pub const VIRTIO_GPU_CONTEXT_INIT_CAPSET_ID_MASK: u32 = 0x0000_00ff;
+
+/// The request of `VIRTIO_GPU_CMD_CTX_CREATE`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuCtxCreate {
+    hdr: VirtioGpuCtrlHdr,
+    nlen: u32,
+    context_init: u32,
+    debug_name: [u8; VIRTIO_GPU_CONTEXT_NAME_LEN],
+}
+
+impl VirtioGpuCtxCreate {
+    /// Creates the request.
+    ///
+    /// The debug name is truncated if it is longer than [`VIRTIO_GPU_CONTEXT_NAME_LEN`].
+    pub fn new(ctx_id: u32, name: &str) -> Self {
+        let hdr = VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdCtxCreate).with_ctx_id(ctx_id);
+
+        let nlen = name.len().min(VIRTIO_GPU_CONTEXT_NAME_LEN);
+        let mut debug_name = [0u8; VIRTIO_GPU_CONTEXT_NAME_LEN];
+        debug_name[..nlen].copy_from_slice(&name.as_bytes()[..nlen]);
+
+        Self {
+            hdr,
+            nlen: nlen as u32,
+            context_init: 0,
+            debug_name,
+        }
+    }
+
+    /// Sets the `context_init` field, which selects the context type by its
+    /// capset id.
+    ///
+    /// The field is only used by the device if `VIRTIO_GPU_F_CONTEXT_INIT`
+    /// is negotiated.
+    pub fn with_context_init(mut self, context_init: u32) -> Self {
+        self.context_init = context_init;
+        self
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_CTX_DESTROY`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuCtxDestroy {
+    hdr: VirtioGpuCtrlHdr,
+}
+
+impl VirtioGpuCtxDestroy {
+    pub fn new(ctx_id: u32) -> Self {
+        let hdr = VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdCtxDestroy).with_ctx_id(ctx_id);
+        Self { hdr }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE` and `VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuCtxResource {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
+impl VirtioGpuCtxResource {
+    pub fn new_attach(ctx_id: u32, resource_id: u32) -> Self {
+        Self::new(VirtioGpuCtrlType::CmdCtxAttachResource, ctx_id, resource_id)
+    }
+
+    pub fn new_detach(ctx_id: u32, resource_id: u32) -> Self {
+        Self::new(VirtioGpuCtrlType::CmdCtxDetachResource, ctx_id, resource_id)
+    }
+
+    fn new(type_: VirtioGpuCtrlType, ctx_id: u32, resource_id: u32) -> Self {
+        let hdr = VirtioGpuCtrlHdr::from_type(type_).with_ctx_id(ctx_id);
+        Self {
+            hdr,
+            resource_id,
+            padding: 0,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_SUBMIT_3D`.
+///
+/// The request is followed by `size` bytes of virgl commands.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuCmdSubmit {
+    hdr: VirtioGpuCtrlHdr,
+    size: u32,
+    padding: u32,
+}
+
+impl VirtioGpuCmdSubmit {
+    pub fn new(ctx_id: u32, size: u32) -> Self {
+        let hdr = VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdSubmit3d).with_ctx_id(ctx_id);
+        Self {
+            hdr,
+            size,
+            padding: 0,
+        }
+    }
+
+    /// Makes the request a fenced one.
+    pub fn with_fence(mut self, fence_id: u64) -> Self {
+        self.hdr = self.hdr.with_fence(fence_id);
+        self
+    }
+}
+
+/// Returns the offset of the resource id in the request of a control command,
+/// or `None` if the command does not refer to a resource.
+#[cfg(ktest)]
+pub(super) fn resource_id_offset(cmd_type: u32) -> Option<usize> {
+    let offset = match VirtioGpuCtrlType::try_from(cmd_type).ok()? {
+        VirtioGpuCtrlType::CmdResourceCreate2d => {
+            offset_of!(VirtioGpuResourceCreate2D, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceUnref => offset_of!(VirtioGpuResourceUnref, resource_id),
+        VirtioGpuCtrlType::CmdSetScanout => offset_of!(VirtioGpuSetScanout, resource_id),
+        VirtioGpuCtrlType::CmdResourceFlush => offset_of!(VirtioGpuResourceFlush, resource_id),
+        VirtioGpuCtrlType::CmdTransferToHost2d => {
+            offset_of!(VirtioGpuTransferToHost2D, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceAttachBacking => {
+            offset_of!(VirtioGpuResourceAttachBacking, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceDetachBacking => {
+            offset_of!(VirtioGpuResourceDetachBacking, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceAssignUuid => {
+            offset_of!(VirtioGpuResourceAssignUuid, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceCreateBlob => {
+            offset_of!(VirtioGpuResourceCreateBlob, resource_id)
+        }
+        VirtioGpuCtrlType::CmdSetScanoutBlob => offset_of!(VirtioGpuSetScanoutBlob, resource_id),
+        VirtioGpuCtrlType::CmdCtxAttachResource | VirtioGpuCtrlType::CmdCtxDetachResource => {
+            offset_of!(VirtioGpuCtxResource, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceCreate3d => {
+            offset_of!(VirtioGpuResourceCreate3D, resource_id)
+        }
+        VirtioGpuCtrlType::CmdTransferToHost3d | VirtioGpuCtrlType::CmdTransferFromHost3d => {
+            offset_of!(VirtioGpuTransferHost3D, resource_id)
+        }
+        VirtioGpuCtrlType::CmdResourceMapBlob => offset_of!(VirtioGpuResourceMapBlob, resource_id),
+        VirtioGpuCtrlType::CmdResourceUnmapBlob => {
+            offset_of!(VirtioGpuResourceUnmapBlob, resource_id)
+        }
+        _ => return None,
+    };
+    Some(offset)
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn try_new_checks_bounds() {
+        let bounds = VirtioGpuRect::new(0, 0, 640, 480);
+        assert!(matches!(
+            VirtioGpuRect::try_new(600, 400, 40, 80, &bounds),
+            Ok(rect) if rect == VirtioGpuRect::new(600, 400, 40, 80)
+        ));
+        assert!(matches!(
+            VirtioGpuRect::try_new(601, 0, 40, 1, &bounds),
+            Err(VirtioDeviceError::InvalidRect)
+        ));
+        assert!(matches!(
+            VirtioGpuRect::try_new(0, 0, 0, 480, &bounds),
+            Err(VirtioDeviceError::InvalidRect)
+        ));
+        assert!(!VirtioGpuRect::new(u32::MAX, 0, 2, 1).is_within(&bounds));
+    }
+}
@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{vec, vec::Vec};
+
+use ostd::Pod;
+
+use super::{
+    control::VirtioGpuFormat,
+    header::{VirtioGpuCtrlHdr, VirtioGpuCtrlType},
+};
+
+/// The width and height of a cursor image, in pixels.
+pub const CURSOR_SIZE: u32 = 64;
+
+/// The pixel format of cursor images.
+///
+/// The format carries alpha, which the device uses to blend the cursor over
+/// the scanout, so cursor images can have transparent and translucent pixels.
+pub const CURSOR_FORMAT: VirtioGpuFormat = VirtioGpuFormat::B8G8R8A8Unorm;
+
+const _: () = assert!(CURSOR_FORMAT.has_alpha());
+
+/// Builds a cursor image of an arrow pointing to the top left corner.
+///
+/// The image is in the B8G8R8A8 format, as expected by `set_cursor_image`.
+/// The arrow is white with a black outline, and the rest of the image is
+/// fully transparent. The hot spot is at `(0, 0)`.
+pub fn arrow_cursor_image() -> Vec<u8> {
+    const TRANSPARENT: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
+    const OUTLINE: [u8; 4] = [0x00, 0x00, 0x00, 0xff];
+    const FILL: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+    fn in_arrow(x: i32, y: i32) -> bool {
+        // The head is bounded by the left edge, the 45 degree diagonal and
+        // the edge from (11, 11) down to (0, 16).
+        let in_head = (0..=y).contains(&x) && y <= 16 && (y <= 11 || 5 * x <= 11 * (16 - y));
+        // The tail goes down to the right from the bottom of the head.
+        let in_tail = (11..=21).contains(&y) && (0..=3).contains(&(x - 3 - (y - 11) / 2));
+        in_head || in_tail
+    }
+
+    let size = CURSOR_SIZE as usize;
+    let mut image = vec![0u8; size * size * CURSOR_FORMAT.bytes_per_pixel()];
+    for (index, pixel) in image.chunks_exact_mut(4).enumerate() {
+        let (x, y) = ((index % size) as i32, (index / size) as i32);
+        let color = if !in_arrow(x, y) {
+            TRANSPARENT
+        } else if in_arrow(x - 1, y)
+            && in_arrow(x + 1, y)
+            && in_arrow(x, y - 1)
+            && in_arrow(x, y + 1)
+        {
+            FILL
+        } else {
+            OUTLINE
+        };
+        pixel.copy_from_slice(&color);
+    }
+    image
+}
+
+/// The position of the cursor on a scanout.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod)]
+pub struct VirtioGpuCursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    padding: u32,
+}
+
+impl VirtioGpuCursorPos {
+    pub fn new(scanout_id: u32, x: u32, y: u32) -> Self {
+        Self {
+            scanout_id,
+            x,
+            y,
+            padding: 0,
+        }
+    }
+}
+
+/// The request of `VIRTIO_GPU_CMD_UPDATE_CURSOR` and `VIRTIO_GPU_CMD_MOVE_CURSOR`.
+///
+/// For `VIRTIO_GPU_CMD_MOVE_CURSOR`, only `pos` is used by the device.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod)]
+pub struct VirtioGpuUpdateCursor {
+    hdr: VirtioGpuCtrlHdr,
+    pos: VirtioGpuCursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    padding: u32,
+}
+
+impl VirtioGpuUpdateCursor {
+    /// Creates a request that updates the cursor image and position.
+    pub fn new(pos: VirtioGpuCursorPos, resource_id: u32, hot_x: u32, hot_y: u32) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdUpdateCursor),
+            pos,
+            resource_id,
+            hot_x,
+            hot_y,
+            padding: 0,
+        }
+    }
+
+    /// Returns the position of the cursor.
+    pub fn pos(&self) -> VirtioGpuCursorPos {
+        self.pos
+    }
+
+    /// Returns the resource of the cursor image.
+    pub fn resource_id(&self) -> u32 {
+        self.resource_id
+    }
+
+    /// Moves the cursor of the request to `pos`.
+    pub fn set_pos(&mut self, pos: VirtioGpuCursorPos) {
+        self.pos = pos;
+    }
+
+    /// Returns the type of the command, which is `VIRTIO_GPU_CMD_UPDATE_CURSOR`
+    /// or `VIRTIO_GPU_CMD_MOVE_CURSOR`.
+    pub fn cmd_type(&self) -> u32 {
+        self.hdr.type_
+    }
+
+    /// Creates a request that only moves the cursor.
+    pub fn new_move(pos: VirtioGpuCursorPos) -> Self {
+        Self {
+            hdr: VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdMoveCursor),
+            pos,
+            resource_id: 0,
+            hot_x: 0,
+            hot_y: 0,
+            padding: 0,
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use core::mem::offset_of;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[ktest]
+    fn requests_carry_the_position() {
+        let pos = VirtioGpuCursorPos::new(1, 300, 400);
+        let x = offset_of!(VirtioGpuUpdateCursor, pos) + offset_of!(VirtioGpuCursorPos, x);
+        let y = offset_of!(VirtioGpuUpdateCursor, pos) + offset_of!(VirtioGpuCursorPos, y);
+
+        for req in [
+            VirtioGpuUpdateCursor::new_move(pos),
+            VirtioGpuUpdateCursor::new(pos, 5, 0, 0),
+        ] {
+            let bytes = req.as_bytes();
+            assert_eq!(read_u32(bytes, x), 300);
+            assert_eq!(read_u32(bytes, y), 400);
+        }
+    }
+}
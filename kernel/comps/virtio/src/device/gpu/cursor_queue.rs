@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::{hint::spin_loop, mem::size_of};
+
+use log::trace;
+use ostd::mm::{DmaDirection, DmaStream, DmaStreamSlice, VmIo};
+
+use super::{buffer::alloc_dma_stream, cursor::VirtioGpuUpdateCursor};
+use crate::{
+    device::VirtioDeviceError,
+    queue::{QueueError, VirtQueue},
+};
+
+/// The maximum number of cursor commands in flight.
+///
+/// Each cursor command takes one descriptor, so this bounds the queue size.
+const MAX_IN_FLIGHT: usize = u64::BITS as usize;
+
+const REQUEST_SIZE: usize = size_of::<VirtioGpuUpdateCursor>();
+
+/// The cursor queue.
+///
+/// Cursor commands have no responses, so they are submitted without waiting
+/// for the device. The request of an in-flight command keeps its own slot in
+/// the request buffer until the device has used it.
+#[derive(Debug)]
+pub(super) struct CursorQueue {
+    queue: VirtQueue,
+    requests: DmaStream,
+    slots: Slots,
+}
+
+impl CursorQueue {
+    pub(super) fn new(queue: VirtQueue) -> Result<Self, VirtioDeviceError> {
+        assert!(queue.size() as usize <= MAX_IN_FLIGHT);
+
+        let requests = alloc_dma_stream(MAX_IN_FLIGHT * REQUEST_SIZE, DmaDirection::ToDevice)?;
+
+        Ok(Self {
+            queue,
+            requests,
+            slots: Slots::new(),
+        })
+    }
+
+    /// Submits a request without waiting for the device to use it.
+    ///
+    /// This only spins if the queue is full.
+    pub(super) fn submit(&mut self, req: &VirtioGpuUpdateCursor) -> Result<(), QueueError> {
+        trace!("virtio-gpu: cursor command {:?}", req);
+        self.reclaim();
+        // There are at least as many slots as descriptors, so a free slot
+        // exists once a descriptor is free.
+        while self.queue.available_desc() == 0 {
+            spin_loop();
+            self.reclaim();
+        }
+
+        let slot = self.slots.free_slot();
+        let req_slice = DmaStreamSlice::new(&self.requests, slot * REQUEST_SIZE, REQUEST_SIZE);
+        req_slice.write_val(0, req).unwrap();
+        req_slice.sync().unwrap();
+
+        let token = self.queue.add_dma_buf(&[&req_slice], &[])?;
+        self.slots.occupy(slot, token);
+
+        if self.queue.should_notify() {
+            self.queue.notify();
+        }
+        Ok(())
+    }
+
+    /// Returns whether the device has used all requests, reclaiming them.
+    #[cfg(ktest)]
+    pub(super) fn is_idle(&mut self) -> bool {
+        self.reclaim();
+        self.slots.is_empty()
+    }
+
+    /// Reclaims the requests that the device has used.
+    pub(super) fn reclaim(&mut self) {
+        while self.queue.can_pop() {
+            let (token, _) = self.queue.pop_used().expect("pop used failed");
+            self.slots.release(token);
+        }
+    }
+}
+
+/// The slots of the request buffer that hold the in-flight requests.
+#[derive(Debug)]
+struct Slots {
+    /// The bitmap of the slots that hold in-flight requests.
+    busy: u64,
+    /// The slot of the in-flight request, indexed by its descriptor head.
+    slot_of_token: [u8; MAX_IN_FLIGHT],
+}
+
+impl Slots {
+    fn new() -> Self {
+        Self {
+            busy: 0,
+            slot_of_token: [0; MAX_IN_FLIGHT],
+        }
+    }
+
+    /// Returns the lowest free slot, of which there must be one.
+    fn free_slot(&self) -> usize {
+        debug_assert_ne!(self.busy, u64::MAX, "no free cursor request slot");
+        (!self.busy).trailing_zeros() as usize
+    }
+
+    /// Marks `slot` as held by the request of `token`.
+    fn occupy(&mut self, slot: usize, token: u16) {
+        self.busy |= 1 << slot;
+        self.slot_of_token[token as usize] = slot as u8;
+    }
+
+    /// Frees the slot held by the request of `token`.
+    fn release(&mut self, token: u16) {
+        let slot = self.slot_of_token[token as usize];
+        self.busy &= !(1 << slot);
+    }
+
+    #[cfg(ktest)]
+    fn is_empty(&self) -> bool {
+        self.busy == 0
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::vec::Vec;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn in_flight_requests_keep_distinct_slots() {
+        let mut slots = Slots::new();
+        // The device may hand out the descriptor heads in any order.
+        let tokens: Vec<u16> = (0..8).rev().collect();
+        let mut taken = Vec::new();
+        for &token in &tokens {
+            let slot = slots.free_slot();
+            slots.occupy(slot, token);
+            taken.push(slot);
+        }
+        assert_eq!(taken, (0..8).collect::<Vec<_>>());
+
+        // The slot of a used request is reused, and the others stay taken.
+        let used = tokens[3];
+        slots.release(used);
+        assert_eq!(slots.free_slot(), 3);
+        slots.occupy(3, 20);
+        assert_eq!(slots.free_slot(), 8);
+
+        for token in tokens.into_iter().filter(|&token| token != used) {
+            slots.release(token);
+        }
+        assert!(!slots.is_empty());
+        slots.release(20);
+        assert!(slots.is_empty());
+    }
+}
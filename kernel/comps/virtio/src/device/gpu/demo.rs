@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! The demo drawn on the boot framebuffer with the `gpu_demo` feature.
+//!
+//! It shows that the framebuffer and the cursor work, and is left out of
+//! the builds without the feature.
+
+use alloc::vec::Vec;
+
+use super::{
+    control::VirtioGpuRect,
+    cursor::arrow_cursor_image,
+    device::{FramebufferResource, GPUDevice},
+    draw::FramebufferGuard,
+};
+use crate::device::VirtioDeviceError;
+
+/// Fills the framebuffer with a test pattern with a logo, and shows an arrow
+/// cursor in the middle of it.
+pub(super) fn draw_demo(
+    device: &GPUDevice,
+    framebuffer: &FramebufferResource,
+) -> Result<(), VirtioDeviceError> {
+    let rect = framebuffer.rect();
+
+    let (width, height) = (rect.width() as usize, rect.height() as usize);
+    let mut image = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let red = (x * 0xff / width) as u8;
+            let green = (y * 0xff / height) as u8;
+            image.extend_from_slice(&[red, green, 0x80, 0xff]);
+        }
+    }
+    let guard = framebuffer.lock();
+    guard.present_image(&image, rect.width(), rect.height())?;
+    let logo = draw_logo(&guard);
+    guard.flush_rect(logo)?;
+    drop(guard);
+
+    device.set_cursor_image(
+        framebuffer.scanout_id().unwrap(),
+        &arrow_cursor_image(),
+        0,
+        0,
+        rect.width() / 2,
+        rect.height() / 2,
+    )
+}
+
+/// The cells of the logo, row by row, where `#` is filled.
+const LOGO: [&[u8]; 7] = [
+    b"...#...", b"..#.#..", b".#...#.", b".#####.", b"#.....#", b"#.....#", b"#.....#",
+];
+
+/// Draws the logo in the middle of the framebuffer, at a quarter of its
+/// shorter side, and returns the area of the logo.
+fn draw_logo(guard: &FramebufferGuard) -> VirtioGpuRect {
+    const LOGO_COLOR: u32 = 0xffff_ffff;
+
+    let rect = guard.rect();
+    let cells = LOGO.len() as u32;
+    let cell = (rect.width().min(rect.height()) / 4 / cells).max(1);
+    let size = cell * cells;
+    let x = rect.width().saturating_sub(size) / 2;
+    let y = rect.height().saturating_sub(size) / 2;
+
+    for (row, line) in LOGO.iter().enumerate() {
+        for (column, &mark) in line.iter().enumerate() {
+            if mark == b'#' {
+                let (cell_x, cell_y) = (x + column as u32 * cell, y + row as u32 * cell);
+                guard.fill_rect(VirtioGpuRect::new(cell_x, cell_y, cell, cell), LOGO_COLOR);
+            }
+        }
+    }
+    VirtioGpuRect::new(x, y, size, size)
+}
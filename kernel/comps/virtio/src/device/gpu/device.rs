@@ -0,0 +1,3816 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    string::ToString,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Debug,
+    hint::spin_loop,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use log::{debug, info, trace, warn};
+use ostd::{
+    arch::read_tsc,
+    boot::boot_info,
+    io_mem::IoMem,
+    mm::{DmaDirection, DmaStream, DmaStreamSlice, HasDaddr, Paddr, VmIo, PAGE_SIZE},
+    sync::{LocalIrqDisabled, Mutex, MutexGuard, SpinLock, SpinLockGuard},
+    trap::TrapFrame,
+    Pod,
+};
+use spin::Once;
+
+#[cfg(ktest)]
+use super::control::resource_id_offset;
+use super::{
+    batch::ControlBatch,
+    buffer::{alloc_dma_stream, DmaSliceAllocator},
+    config::{GPUFeatures, VirtioGPUConfig, VIRTIO_GPU_EVENT_DISPLAY},
+    control::{
+        Resource3dParams, SizedResponse, Transfer3dParams, VirtioGpuBox, VirtioGpuCmdSubmit,
+        VirtioGpuCtxCreate, VirtioGpuCtxDestroy, VirtioGpuCtxResource, VirtioGpuFormat,
+        VirtioGpuGetCapsetInfo, VirtioGpuGetEdid, VirtioGpuMemEntry, VirtioGpuRect,
+        VirtioGpuResourceAssignUuid, VirtioGpuResourceAttachBacking, VirtioGpuResourceCreate2D,
+        VirtioGpuResourceCreate3D, VirtioGpuResourceCreateBlob, VirtioGpuResourceDetachBacking,
+        VirtioGpuResourceFlush, VirtioGpuResourceMapBlob, VirtioGpuResourceUnmapBlob,
+        VirtioGpuResourceUnref, VirtioGpuRespCapsetInfo, VirtioGpuRespDisplayInfo,
+        VirtioGpuRespEdid, VirtioGpuRespMapInfo, VirtioGpuRespResourceUuid, VirtioGpuSetScanout,
+        VirtioGpuSetScanoutBlob, VirtioGpuTransferHost3D, VirtioGpuTransferToHost2D,
+        VIRTIO_GPU_BLOB_FLAG_USE_SHAREABLE, VIRTIO_GPU_BLOB_MEM_GUEST, VIRTIO_GPU_BLOB_MEM_HOST3D,
+        VIRTIO_GPU_MAP_CACHE_MASK, VIRTIO_GPU_MAX_EDID_SIZE, VIRTIO_GPU_MAX_SCANOUTS,
+        VIRTIO_GPU_SHM_ID_HOST_VISIBLE,
+    },
+    cursor::{VirtioGpuCursorPos, VirtioGpuUpdateCursor, CURSOR_FORMAT, CURSOR_SIZE},
+    cursor_queue::CursorQueue,
+    draw::{clip_rect, pixel_offset, FramebufferGuard, PixelFormat, Shadow},
+    edid::Edid,
+    fence::{is_newer, FenceTracker},
+    header::{VirtioGpuCtrlHdr, VirtioGpuCtrlType},
+    metrics::{duration_to_cycles, CommandCounters, GpuMetrics},
+    resource::ResourceInfo,
+    rotation::Rotation,
+    software_cursor::{union_rect, CoveredPixels, SoftwareCursor},
+    QUEUE_CONTROL, QUEUE_CURSOR,
+};
+use crate::{
+    device::VirtioDeviceError,
+    queue::VirtQueue,
+    transport::{ConfigManager, DeviceStatus, VirtioTransport},
+};
+
+/// The pixel format of the framebuffers set up by the driver.
+const FRAMEBUFFER_FORMAT: VirtioGpuFormat = VirtioGpuFormat::B8G8R8A8Unorm;
+
+/// The resolution of the framebuffer if the scanout reports no size, e.g., on
+/// a headless host, or if the guest is out of memory for the resolution of the
+/// scanout.
+///
+/// The specification suggests 1024x768 if no display information is available.
+const FALLBACK_RESOLUTION: (u32, u32) = (1024, 768);
+
+/// The largest resolution of the framebuffer and other 2D resources, unless the
+/// `virtio_gpu.max_resolution=WIDTHxHEIGHT` kernel command-line argument
+/// gives another one.
+///
+/// This bounds the guest memory that the driver allocates for the sizes
+/// reported by the device, which may be bogus.
+const DEFAULT_MAX_RESOLUTION: (u32, u32) = (4096, 4096);
+
+type EventHandler = fn(&GPUDevice);
+
+/// The handlers of the events that the device signals in `events_read`.
+///
+/// A new event type is handled by adding its bit and handler here.
+const EVENT_HANDLERS: &[(u32, EventHandler)] =
+    &[(VIRTIO_GPU_EVENT_DISPLAY, GPUDevice::handle_display_event)];
+
+/// A virtio GPU device.
+///
+/// The device provides 2D (and, optionally, virgl 3D) rendering to resources
+/// that live on the host, and displays the resources on its scanouts.
+#[derive(Debug)]
+pub struct GPUDevice {
+    config_manager: ConfigManager<VirtioGPUConfig>,
+    transport: SpinLock<Box<dyn VirtioTransport>>,
+    /// Whether the virtqueues are usable, which they are not before the
+    /// driver has finished initializing the device, while the device is being
+    /// reset, or after a reset has failed.
+    ready: AtomicBool,
+    /// The queue for all commands except the cursor ones.
+    control_queue: SpinLock<VirtQueue>,
+    /// The queue for cursor commands, which is a fast track that cursor
+    /// updates do not have to wait behind the (slow) control commands.
+    ///
+    /// If the device has no usable cursor queue, this is `None` and the
+    /// driver draws the cursor on the framebuffer instead.
+    cursor_queue: SpinLock<Option<CursorQueue>>,
+    /// The buffer for requests on the control queue.
+    control_request: DmaStream,
+    /// The buffer for responses on the control queue.
+    control_response: DmaStream,
+    /// The negotiated features.
+    features: GPUFeatures,
+    /// The next free resource id. Resource id 0 is reserved for "no resource".
+    next_resource_id: AtomicU32,
+    /// The 2D resources that the driver has created on the host, by their ids.
+    resources: SpinLock<BTreeMap<u32, ResourceInfo>>,
+    /// The resource and its area displayed on each scanout, or `None` if the
+    /// scanout is disabled.
+    scanouts: SpinLock<[Option<(u32, VirtioGpuRect)>; VIRTIO_GPU_MAX_SCANOUTS]>,
+    /// The color shown around the areas of resources that are smaller than
+    /// their displays, see [`Self::set_scanout_border`].
+    scanout_border: SpinLock<Option<u32>>,
+    /// The fence ids of the fenced commands.
+    fences: FenceTracker,
+    /// The fence id of the last frame presented by [`Self::present_and_wait`].
+    last_present_fence: AtomicU64,
+    /// The rotation of the framebuffers set up afterward.
+    rotation: SpinLock<Rotation>,
+    /// The framebuffer set up last, if any, which the device-wide flushes use.
+    framebuffer: SpinLock<Option<Framebuffer>>,
+    /// The framebuffer displayed on each scanout, see [`Self::flush_scanout`].
+    scanout_framebuffers: SpinLock<[Option<Framebuffer>; VIRTIO_GPU_MAX_SCANOUTS]>,
+    /// The framebuffer set up at boot, which is kept so that it stays on the display.
+    boot_framebuffer: SpinLock<Option<FramebufferResource>>,
+    /// The resource of the current cursor image.
+    cursor_resource: SpinLock<Option<u32>>,
+    /// Whether cursor moves are coalesced, see [`Self::set_cursor_coalescing`].
+    cursor_coalescing: AtomicBool,
+    /// The latest position of the hardware cursor that has not been sent yet.
+    pending_cursor_move: SpinLock<Option<VirtioGpuCursorPos>>,
+    /// The latest update of the hardware cursor to an image, at the latest
+    /// position, which [`Self::show_cursor`] sends again.
+    cursor_update: SpinLock<Option<VirtioGpuUpdateCursor>>,
+    /// Whether the cursor is hidden, see [`Self::hide_cursor`].
+    cursor_hidden: AtomicBool,
+    /// The parsed EDID of each scanout, cached by [`Self::edid`].
+    edids: SpinLock<[Option<Edid>; VIRTIO_GPU_MAX_SCANOUTS]>,
+    /// The EDID blob of each scanout, cached by [`Self::raw_edid`].
+    raw_edids: SpinLock<[Option<Vec<u8>>; VIRTIO_GPU_MAX_SCANOUTS]>,
+    /// The display info, cached by [`Self::display_info`].
+    display_info: SpinLock<Option<VirtioGpuRespDisplayInfo>>,
+    /// The number of display events that the device has signaled.
+    display_events: AtomicU64,
+    /// The cursor drawn by the driver if there is no cursor queue.
+    software_cursor: SpinLock<SoftwareCursor>,
+    /// The shared memory region where host blobs are mapped, if the device
+    /// has one and [`GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB`] is negotiated.
+    host_visible: Option<IoMem>,
+    /// The sizes of the blobs of host memory, by their resource ids.
+    host_blobs: SpinLock<BTreeMap<u32, u64>>,
+    /// The 3D resources created by [`Self::resource_create_3d`].
+    resources_3d: SpinLock<BTreeSet<u32>>,
+    /// The formats accepted by the host, probed by [`Self::supported_formats`].
+    supported_formats: Once<Vec<VirtioGpuFormat>>,
+    /// Whether the host can render 3D, probed by [`Self::has_3d`].
+    has_3d: Once<bool>,
+    /// The id and the maximum version of each capability set, cached by
+    /// [`Self::capsets`].
+    capsets: SpinLock<Option<Vec<(u32, u32)>>>,
+    /// The largest resource, cached by [`Self::max_resolution`].
+    max_resolution: Once<(u32, u32)>,
+    /// The counters of the commands sent to the device.
+    metrics: CommandCounters,
+    /// The types and the resource ids (or 0) of the commands sent to the
+    /// device while tests record them.
+    #[cfg(ktest)]
+    command_log: SpinLock<Option<Vec<(u32, u32)>>>,
+    /// Whether a test holds the control queue, see [`Self::lock_control_queue`].
+    #[cfg(ktest)]
+    control_queue_held: AtomicBool,
+}
+
+/// The resource displayed on a scanout, returned by [`GPUDevice::current_scanout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanoutState {
+    /// The id of the displayed resource.
+    pub resource_id: u32,
+    /// The area of the resource that is displayed.
+    pub rect: VirtioGpuRect,
+}
+
+/// A shared memory region of the device, which is device memory mapped into
+/// the guest address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmRegion {
+    /// The id of the region, e.g., [`VIRTIO_GPU_SHM_ID_HOST_VISIBLE`].
+    pub id: u8,
+    /// The physical address of the region.
+    pub addr: Paddr,
+    /// The length of the region in bytes.
+    pub length: u64,
+}
+
+/// The host resource of a framebuffer set up by [`GPUDevice::setup_framebuffer`].
+///
+/// The guest backing storage is detached from the resource and the resource is
+/// destroyed on the host when this is dropped.
+#[derive(Debug)]
+pub struct FramebufferResource {
+    device: Weak<GPUDevice>,
+    scanout_id: Option<u32>,
+    framebuffer: Framebuffer,
+    /// The copy of the pixels in regular memory, see [`Self::enable_shadow`].
+    shadow: Option<Shadow>,
+}
+
+impl FramebufferResource {
+    /// Returns the resource id of the framebuffer.
+    pub fn resource_id(&self) -> u32 {
+        self.framebuffer.resource_id
+    }
+
+    /// Returns the scanout that displays the framebuffer, or `None` if the
+    /// framebuffer is set up by [`GPUDevice::setup_offscreen`].
+    pub fn scanout_id(&self) -> Option<u32> {
+        self.scanout_id
+    }
+
+    /// Returns the size of the framebuffer as a rectangle at the origin.
+    pub fn rect(&self) -> VirtioGpuRect {
+        self.framebuffer.rect
+    }
+
+    /// Returns the pixel format of the framebuffer.
+    pub fn format(&self) -> PixelFormat {
+        self.framebuffer.format.into()
+    }
+
+    /// Returns the number of bytes of a row of the framebuffer.
+    pub fn stride(&self) -> usize {
+        self.framebuffer.stride()
+    }
+
+    /// Returns the contents of the framebuffer as `R8G8B8A8` pixels, row by row.
+    ///
+    /// See [`GPUDevice::capture`]. If the framebuffer has a shadow, the
+    /// contents are what has been flushed.
+    pub fn capture(&self) -> Vec<u8> {
+        self.framebuffer.capture()
+    }
+
+    /// Makes the framebuffer draw on a copy of its pixels in regular memory.
+    ///
+    /// The drawing primitives then write to the copy, which is cheap to read
+    /// back with [`FramebufferGuard::read_pixel`], and the areas drawn on are
+    /// copied to the guest backing storage when they are flushed, e.g., by
+    /// [`FramebufferGuard::present`]. Enabling the shadow again does nothing.
+    ///
+    /// Fails with [`VirtioDeviceError::GuestOutOfMemory`] if the memory of the
+    /// copy cannot be allocated.
+    pub fn enable_shadow(&mut self) -> Result<(), VirtioDeviceError> {
+        if self.shadow.is_none() {
+            let len = self.stride() * self.rect().height() as usize;
+            self.shadow = Some(Shadow::new(&self.framebuffer.backing, len)?);
+        }
+        Ok(())
+    }
+
+    /// Locks the framebuffer for drawing.
+    ///
+    /// The pixels of the framebuffer are only accessible through the returned
+    /// guard, which is exclusive, so multiple threads can draw and flush
+    /// without tearing each other's output.
+    pub fn lock(&self) -> FramebufferGuard<'_> {
+        FramebufferGuard::new(self, self.framebuffer.draw_lock.lock())
+    }
+
+    pub(super) fn backing(&self) -> &DmaStream {
+        &self.framebuffer.backing
+    }
+
+    pub(super) fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    pub(super) fn shadow(&self) -> Option<&Shadow> {
+        self.shadow.as_ref()
+    }
+
+    pub(super) fn device(&self) -> Option<Arc<GPUDevice>> {
+        self.device.upgrade()
+    }
+}
+
+impl Drop for FramebufferResource {
+    fn drop(&mut self) {
+        let Some(device) = self.device.upgrade() else {
+            return;
+        };
+
+        let resource_id = self.framebuffer.resource_id;
+        {
+            let mut framebuffer = device.framebuffer.disable_irq().lock();
+            if framebuffer
+                .as_ref()
+                .is_some_and(|framebuffer| framebuffer.resource_id == resource_id)
+            {
+                *framebuffer = None;
+            }
+        }
+        for framebuffer in device.scanout_framebuffers.disable_irq().lock().iter_mut() {
+            if framebuffer
+                .as_ref()
+                .is_some_and(|framebuffer| framebuffer.resource_id == resource_id)
+            {
+                *framebuffer = None;
+            }
+        }
+
+        // The backing of a blob resource is released along with the resource.
+        if !self.framebuffer.blob {
+            if let Err(err) = device.resource_detach_backing(resource_id) {
+                warn!(
+                    "failed to detach the backing of framebuffer resource {}: {:?}",
+                    resource_id, err
+                );
+            }
+        }
+        if let Err(err) = device.resource_unref(resource_id) {
+            warn!(
+                "failed to release framebuffer resource {}: {:?}",
+                resource_id, err
+            );
+        }
+    }
+}
+
+/// A framebuffer that the driver has set up.
+#[derive(Debug, Clone)]
+pub(super) struct Framebuffer {
+    resource_id: u32,
+    /// The size of the framebuffer as drawn, i.e., before the rotation.
+    rect: VirtioGpuRect,
+    format: VirtioGpuFormat,
+    backing: DmaStream,
+    blob: bool,
+    rotation: Rotation,
+    /// The backing storage of the host resource, to which the pixels are
+    /// copied rotated, or `None` if the framebuffer is not rotated.
+    rotated_backing: Option<DmaStream>,
+    /// Whether the framebuffer is not displayed on any scanout.
+    offscreen: bool,
+    /// The lock that serializes drawing on and flushing the framebuffer.
+    draw_lock: Arc<Mutex<()>>,
+}
+
+impl Framebuffer {
+    pub(super) fn resource_id(&self) -> u32 {
+        self.resource_id
+    }
+
+    /// Returns whether the framebuffer is a blob resource, whose contents need
+    /// no transfers to the host.
+    pub(super) fn is_blob(&self) -> bool {
+        self.blob
+    }
+
+    pub(super) fn format(&self) -> VirtioGpuFormat {
+        self.format
+    }
+
+    /// Returns whether the framebuffer is not displayed, so it needs no flushes.
+    pub(super) fn is_offscreen(&self) -> bool {
+        self.offscreen
+    }
+
+    fn stride(&self) -> usize {
+        self.rect.width() as usize * self.format.bytes_per_pixel()
+    }
+
+    /// Returns the backing storage attached to the host resource.
+    fn host_backing(&self) -> &DmaStream {
+        self.rotated_backing.as_ref().unwrap_or(&self.backing)
+    }
+
+    /// Returns the number of bytes of a row of the host resource.
+    fn host_stride(&self) -> usize {
+        self.rotation.rotate_size(self.rect).width() as usize * self.format.bytes_per_pixel()
+    }
+
+    fn capture(&self) -> Vec<u8> {
+        let format = self.format;
+
+        // The rows of the backing storage are packed, so it can be read at once.
+        let len = self.stride() * self.rect.height() as usize;
+        self.backing.sync(0..len).unwrap();
+        let mut pixels = vec![0u8; len];
+        self.backing.read_bytes(0, &mut pixels).unwrap();
+
+        for pixel in pixels.chunks_exact_mut(format.bytes_per_pixel()) {
+            let rgba = format.to_rgba(pixel.try_into().unwrap());
+            pixel.copy_from_slice(&rgba);
+        }
+        pixels
+    }
+}
+
+/// A flush of a framebuffer area whose pixels have been synced to the device.
+///
+/// The software cursor may be composited over the pixels, so the area of a
+/// prepared flush must be copied to the host before [`Self::finish`] erases
+/// the cursor again.
+pub(super) struct PreparedFlush<'a> {
+    framebuffer: &'a Framebuffer,
+    rect: VirtioGpuRect,
+    offset: u64,
+    covered: Option<CoveredPixels>,
+    _draw_guard: Option<MutexGuard<'a, ()>>,
+}
+
+impl PreparedFlush<'_> {
+    /// Returns the area of the host resource to copy and flush, which includes
+    /// the areas where the software cursor must be updated.
+    pub(super) fn rect(&self) -> VirtioGpuRect {
+        self.rect
+    }
+
+    /// Returns the offset of the area in the backing storage of the host resource.
+    pub(super) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns whether the software cursor is composited over the area.
+    pub(super) fn has_cursor(&self) -> bool {
+        self.covered.is_some()
+    }
+
+    /// Erases the software cursor from the backing storage.
+    pub(super) fn finish(self) {
+        if let Some(covered) = self.covered {
+            covered.restore(&self.framebuffer.backing, self.framebuffer.stride());
+        }
+    }
+}
+
+impl GPUDevice {
+    const QUEUE_SIZE: u16 = 64;
+
+    /// Negotiates features for the device specified bits 0~23.
+    pub(crate) fn negotiate_features(features: u64) -> u64 {
+        let offered = GPUFeatures::from_bits_truncate(features);
+        let accepted = offered & GPUFeatures::SUPPORTED;
+        debug!(
+            "virtio_gpu_features offered = {:?} (raw {:#x}), accepted = {:?}",
+            offered, features, accepted
+        );
+        accepted.bits()
+    }
+
+    /// Creates and initializes the device.
+    ///
+    /// The device is registered as [`super::DEVICE_NAME`], where the rest of
+    /// the kernel can find it with [`super::get_device`].
+    ///
+    /// The device is initialized in the order that the virtio specification
+    /// requires: the features are negotiated (by the transport, before this is
+    /// called), then the virtqueues are created, the DMA buffers allocated and
+    /// the interrupt callbacks registered, and only then is `DRIVER_OK` set by
+    /// `finish_init`. The device need not process any request before that, so
+    /// commands fail with [`VirtioDeviceError::NotReady`] until then, instead of
+    /// waiting forever for a response.
+    pub fn init(mut transport: Box<dyn VirtioTransport>) -> Result<Arc<Self>, VirtioDeviceError> {
+        let config_manager = VirtioGPUConfig::new_manager(transport.as_ref());
+        debug!("virtio_gpu_config = {:?}", config_manager.read_config());
+
+        let features = Self::accepted_features(transport.as_ref());
+        let (control_queue, cursor_queue) = Self::create_queues(transport.as_mut())?;
+        for (id, io_mem) in transport.shared_memory_regions() {
+            debug!(
+                "virtio gpu shared memory region {}: paddr = {:#x}, length = {:#x}",
+                id,
+                io_mem.paddr(),
+                io_mem.length()
+            );
+        }
+        let host_visible = if features.contains(GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB) {
+            transport.shared_memory_region(VIRTIO_GPU_SHM_ID_HOST_VISIBLE)
+        } else {
+            None
+        };
+
+        let control_request = alloc_dma_stream(PAGE_SIZE, DmaDirection::ToDevice)?;
+        let control_response = alloc_dma_stream(PAGE_SIZE, DmaDirection::Bidirectional)?;
+
+        let device = Arc::new(Self {
+            config_manager,
+            transport: SpinLock::new(transport),
+            ready: AtomicBool::new(false),
+            control_queue: SpinLock::new(control_queue),
+            cursor_queue: SpinLock::new(cursor_queue),
+            control_request,
+            control_response,
+            features,
+            next_resource_id: AtomicU32::new(1),
+            resources: SpinLock::new(BTreeMap::new()),
+            scanouts: SpinLock::new([None; VIRTIO_GPU_MAX_SCANOUTS]),
+            scanout_border: SpinLock::new(None),
+            fences: FenceTracker::new(),
+            last_present_fence: AtomicU64::new(0),
+            rotation: SpinLock::new(Rotation::Deg0),
+            framebuffer: SpinLock::new(None),
+            scanout_framebuffers: SpinLock::new([const { None }; VIRTIO_GPU_MAX_SCANOUTS]),
+            boot_framebuffer: SpinLock::new(None),
+            cursor_resource: SpinLock::new(None),
+            cursor_coalescing: AtomicBool::new(false),
+            pending_cursor_move: SpinLock::new(None),
+            cursor_update: SpinLock::new(None),
+            cursor_hidden: AtomicBool::new(false),
+            edids: SpinLock::new([const { None }; VIRTIO_GPU_MAX_SCANOUTS]),
+            raw_edids: SpinLock::new([const { None }; VIRTIO_GPU_MAX_SCANOUTS]),
+            display_info: SpinLock::new(None),
+            display_events: AtomicU64::new(0),
+            software_cursor: SpinLock::new(SoftwareCursor::new()),
+            host_visible,
+            host_blobs: SpinLock::new(BTreeMap::new()),
+            resources_3d: SpinLock::new(BTreeSet::new()),
+            supported_formats: Once::new(),
+            has_3d: Once::new(),
+            capsets: SpinLock::new(None),
+            max_resolution: Once::new(),
+            metrics: CommandCounters::new(),
+            #[cfg(ktest)]
+            command_log: SpinLock::new(None),
+            #[cfg(ktest)]
+            control_queue_held: AtomicBool::new(false),
+        });
+
+        let has_cursor_queue = device.has_hardware_cursor();
+        let mut transport = device.transport.disable_irq().lock();
+        device.register_callbacks(transport.as_mut(), has_cursor_queue, true);
+        transport.finish_init();
+        device.ready.store(true, Ordering::Release);
+        drop(transport);
+
+        // The device stays usable without a boot framebuffer, e.g., if it is
+        // headless or the command line names a bad scanout.
+        match init_frame_buffer(&device, scanout_from_cmdline()) {
+            Ok(framebuffer) => *device.boot_framebuffer.disable_irq().lock() = Some(framebuffer),
+            Err(err) => warn!(
+                "failed to set up the virtio gpu boot framebuffer: {:?}",
+                err
+            ),
+        }
+
+        super::register_device(super::DEVICE_NAME.to_string(), device.clone());
+        Ok(device)
+    }
+
+    /// Resets the device and initializes it again.
+    ///
+    /// This recovers the device from a bad state, e.g., after it has signaled
+    /// that it needs a reset (see [`Self::needs_reset`]). The features are
+    /// negotiated again and the virtqueues are recreated. The shared DMA buffers
+    /// are guest memory, which a reset does not affect, so they are kept.
+    ///
+    /// The reset destroys all resources on the host. The 2D resources created
+    /// by the driver are recreated with the same ids and backing storage, and
+    /// the scanouts display them again. The cursor must be shown again with
+    /// [`Self::update_cursor`], and 3D contexts must be set up again by their
+    /// users.
+    ///
+    /// Commands made while the device is being reset, or after the reset has
+    /// failed, fail with [`VirtioDeviceError::NotReady`].
+    pub fn reset(self: &Arc<Self>) -> Result<(), VirtioDeviceError> {
+        {
+            // No command can be in flight while the queues are locked.
+            let mut control_queue = self.lock_control_queue();
+            let mut cursor_queue = self.cursor_queue.disable_irq().lock();
+            let mut transport = self.transport.disable_irq().lock();
+
+            // The queues are only usable again if the reset succeeds.
+            self.ready.store(false, Ordering::Release);
+            crate::reset_and_negotiate(&mut transport);
+            if Self::accepted_features(transport.as_ref()) != self.features {
+                warn!("virtio gpu features changed after the reset");
+                // Tell the device that the driver has given up on it.
+                let status = transport.read_device_status() | DeviceStatus::FAILED;
+                transport.write_device_status(status).unwrap();
+                return Err(VirtioDeviceError::FeatureNotSupported);
+            }
+
+            let (new_control_queue, new_cursor_queue) = Self::create_queues(transport.as_mut())?;
+            *control_queue = new_control_queue;
+            *cursor_queue = new_cursor_queue;
+
+            self.register_callbacks(transport.as_mut(), cursor_queue.is_some(), false);
+            transport.finish_init();
+            self.ready.store(true, Ordering::Release);
+        }
+
+        // The displays may have changed while the device was broken.
+        *self.edids.disable_irq().lock() = [const { None }; VIRTIO_GPU_MAX_SCANOUTS];
+        *self.raw_edids.disable_irq().lock() = [const { None }; VIRTIO_GPU_MAX_SCANOUTS];
+        *self.display_info.disable_irq().lock() = None;
+        *self.capsets.disable_irq().lock() = None;
+        // The host memory of the blobs is gone.
+        self.host_blobs.disable_irq().lock().clear();
+        self.resources_3d.disable_irq().lock().clear();
+        let resources = core::mem::take(&mut *self.resources.disable_irq().lock());
+        let scanouts = core::mem::replace(
+            &mut *self.scanouts.disable_irq().lock(),
+            [None; VIRTIO_GPU_MAX_SCANOUTS],
+        );
+        self.restore_resources(&resources, &scanouts)
+    }
+
+    /// Takes the framebuffer set up at boot, e.g., to hand it over to a console.
+    ///
+    /// The driver keeps the boot framebuffer on the display until it is taken.
+    /// Afterward, it is released when the returned handle is dropped. Returns
+    /// `None` if it has been taken, or could not be set up at boot.
+    pub fn take_boot_framebuffer(&self) -> Option<FramebufferResource> {
+        self.boot_framebuffer.disable_irq().lock().take()
+    }
+
+    /// Checks that the virtqueues are usable.
+    ///
+    /// Fails with [`VirtioDeviceError::NotReady`] if the device has not been
+    /// initialized yet, is being reset or the last reset has failed.
+    ///
+    /// The command helpers check this with the queue locked, since a reset
+    /// replaces the queues with the queues locked.
+    pub(super) fn check_ready(&self) -> Result<(), VirtioDeviceError> {
+        if self.ready.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            Err(VirtioDeviceError::NotReady)
+        }
+    }
+
+    /// Returns whether the device has signaled that it needs a reset.
+    pub fn needs_reset(&self) -> bool {
+        self.transport
+            .disable_irq()
+            .lock()
+            .read_device_status()
+            .contains(DeviceStatus::DEVICE_NEEDS_RESET)
+    }
+
+    /// Returns the features that `negotiate_features` has accepted.
+    fn accepted_features(transport: &dyn VirtioTransport) -> GPUFeatures {
+        // The features have been negotiated and logged by `negotiate_features`,
+        // this only computes the result again.
+        GPUFeatures::from_bits_truncate(transport.read_device_features()) & GPUFeatures::SUPPORTED
+    }
+
+    /// Creates the virtqueues.
+    ///
+    /// The cursor queue is `None` if the device does not offer a usable one.
+    fn create_queues(
+        transport: &mut dyn VirtioTransport,
+    ) -> Result<(VirtQueue, Option<CursorQueue>), VirtioDeviceError> {
+        let control_queue = VirtQueue::new(QUEUE_CONTROL, Self::QUEUE_SIZE, transport)?;
+
+        let cursor_queue = if !matches!(transport.max_queue_size(QUEUE_CURSOR), Ok(size) if size > 0)
+        {
+            warn!("virtio gpu has no cursor queue, falling back to the software cursor");
+            None
+        } else {
+            match VirtQueue::new(QUEUE_CURSOR, Self::QUEUE_SIZE, transport) {
+                Ok(queue) => Some(CursorQueue::new(queue)?),
+                Err(err) => {
+                    warn!(
+                        "failed to set up the virtio gpu cursor queue ({:?}), falling back to the software cursor",
+                        err
+                    );
+                    None
+                }
+            }
+        };
+
+        Ok((control_queue, cursor_queue))
+    }
+
+    /// Returns whether the cursor is shown by the device.
+    ///
+    /// Otherwise, the driver draws the cursor on the framebuffer.
+    pub fn has_hardware_cursor(&self) -> bool {
+        self.cursor_queue.disable_irq().lock().is_some()
+    }
+
+    /// Registers the interrupt callbacks of the device.
+    ///
+    /// The transport cannot unregister callbacks, so after a reset the
+    /// callbacks registered before may also be called. This is harmless, since
+    /// the callbacks only poll the device state.
+    ///
+    /// The cursor queue gets an IRQ line of its own only if `first` is true,
+    /// i.e., at initialization. Each such request takes another line of the
+    /// transport, and the line taken at initialization cannot be handed back,
+    /// so the cursor queue shares the IRQ line of the control queue after a
+    /// reset.
+    fn register_callbacks(
+        self: &Arc<Self>,
+        transport: &mut dyn VirtioTransport,
+        has_cursor_queue: bool,
+        first: bool,
+    ) {
+        let handle_queue_irq = |index: u16| {
+            let clone_device = self.clone();
+            move |_: &TrapFrame| clone_device.handle_queue_irq(index)
+        };
+        let handle_config_change = {
+            let clone_device = self.clone();
+            move |_: &TrapFrame| clone_device.handle_config_change()
+        };
+
+        transport
+            .register_queue_callback(
+                QUEUE_CONTROL,
+                Box::new(handle_queue_irq(QUEUE_CONTROL)),
+                false,
+            )
+            .unwrap();
+        // The cursor queue asks for an IRQ line of its own, so reclaiming its
+        // requests is not held up by the interrupts of the control queue.
+        if has_cursor_queue {
+            transport
+                .register_queue_callback(
+                    QUEUE_CURSOR,
+                    Box::new(handle_queue_irq(QUEUE_CURSOR)),
+                    first,
+                )
+                .unwrap();
+        }
+        transport
+            .register_cfg_callback(Box::new(handle_config_change))
+            .unwrap();
+    }
+
+    /// Recreates the resources on the host and displays them on the scanouts
+    /// again after a reset.
+    fn restore_resources(
+        &self,
+        resources: &BTreeMap<u32, ResourceInfo>,
+        scanouts: &[Option<(u32, VirtioGpuRect)>],
+    ) -> Result<(), VirtioDeviceError> {
+        for (&resource_id, info) in resources {
+            if info.is_blob() {
+                let backing = info.backing().unwrap();
+                self.resource_create_blob(
+                    resource_id,
+                    info.format(),
+                    info.width(),
+                    info.height(),
+                    backing,
+                )?;
+                backing.sync(0..backing.nbytes()).unwrap();
+                continue;
+            }
+
+            self.resource_create_2d_with_stride(
+                resource_id,
+                info.format(),
+                info.width(),
+                info.height(),
+                info.stride(),
+            )?;
+            if let Some(backing) = info.backing() {
+                self.resource_attach_backing(resource_id, backing)?;
+                backing.sync(0..backing.nbytes()).unwrap();
+                self.transfer_to_host_2d(info.rect(), 0, resource_id)?;
+            }
+        }
+
+        for (scanout_id, scanout) in scanouts.iter().enumerate() {
+            let Some((resource_id, rect)) = *scanout else {
+                continue;
+            };
+            self.set_scanout(scanout_id as u32, resource_id, rect)?;
+            self.resource_flush(rect, resource_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the feature is negotiated.
+    pub fn supports(&self, feature: GPUFeatures) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// Returns all features negotiated with the device.
+    ///
+    /// This lets, e.g., tests skip the checks of features that the device does
+    /// not offer. Use [`Self::supports`] to check for a single feature.
+    pub fn negotiated_features(&self) -> GPUFeatures {
+        self.features
+    }
+
+    /// Returns the number of scanouts advertised in the device configuration.
+    ///
+    /// Only the scanouts with smaller ids exist, whatever the display
+    /// information reports for the others.
+    pub fn config_num_scanouts(&self) -> u32 {
+        let num_scanouts = self.config_manager.read_config().num_scanouts;
+        num_scanouts.min(VIRTIO_GPU_MAX_SCANOUTS as u32)
+    }
+
+    /// Returns the number of capability sets reported by the device.
+    pub fn config_num_capsets(&self) -> u32 {
+        self.config_manager.read_config().num_capsets
+    }
+
+    /// Returns the width and the height of the largest 2D resource that the
+    /// driver creates.
+    ///
+    /// The configuration space of virtio-gpu does not advertise a limit, so
+    /// this is the one given by the `virtio_gpu.max_resolution=WIDTHxHEIGHT`
+    /// kernel command-line argument, or 4096x4096 by default.
+    pub fn max_resolution(&self) -> (u32, u32) {
+        *self.max_resolution.call_once(max_resolution_from_cmdline)
+    }
+
+    /// Fails with [`VirtioDeviceError::InvalidRect`] if a resource of the given
+    /// size is larger than [`Self::max_resolution`].
+    ///
+    /// This is checked before allocating the guest backing storage, which would
+    /// be wasted if the host rejected the resource.
+    pub(super) fn check_resource_size(
+        &self,
+        width: u32,
+        height: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        let (max_width, max_height) = self.max_resolution();
+        if width > max_width || height > max_height {
+            warn!(
+                "virtio gpu resource of {}x{} is larger than the maximum {}x{}",
+                width, height, max_width, max_height
+            );
+            return Err(VirtioDeviceError::InvalidRect);
+        }
+        Ok(())
+    }
+
+    /// Returns [`VirtioDeviceError::FeatureNotSupported`] if the feature is not negotiated.
+    fn require(&self, feature: GPUFeatures) -> Result<(), VirtioDeviceError> {
+        if self.supports(feature) {
+            Ok(())
+        } else {
+            Err(VirtioDeviceError::FeatureNotSupported)
+        }
+    }
+
+    /// Allocates an unused resource id.
+    pub fn alloc_resource_id(&self) -> u32 {
+        self.next_resource_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Makes the resource ids be allocated from `first_resource_id` on.
+    ///
+    /// This makes the resource ids in the commands of a test reproducible,
+    /// regardless of the order in which the tests run. The caller must pick
+    /// ids above those of the resources that are still alive.
+    ///
+    /// Returns [`VirtioDeviceError::InvalidResource`] if `first_resource_id`
+    /// is 0, which means "no resource".
+    #[cfg(ktest)]
+    pub(super) fn seed_resource_ids(
+        &self,
+        first_resource_id: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        if first_resource_id == 0 {
+            return Err(VirtioDeviceError::InvalidResource(0));
+        }
+        self.next_resource_id
+            .store(first_resource_id, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Allocates an unused fence id.
+    fn alloc_fence_id(&self) -> u64 {
+        self.fences.alloc()
+    }
+
+    /// Services the used ring of the queue at `index`, which has interrupted.
+    ///
+    /// Only that queue is touched, so, e.g., an interrupt of the cursor queue
+    /// does not take the lock of the control queue.
+    fn handle_queue_irq(&self, index: u16) {
+        match index {
+            QUEUE_CONTROL => self.handle_control_irq(),
+            QUEUE_CURSOR => self.handle_cursor_irq(),
+            _ => warn!("Virtio GPU device interrupted by unknown queue {}", index),
+        }
+    }
+
+    fn handle_control_irq(&self) {
+        // Control commands are completed synchronously by polling the used ring,
+        // so there is nothing to do here.
+        trace!("Virtio GPU device handle control queue irq");
+    }
+
+    fn handle_cursor_irq(&self) {
+        if let Some(cursor_queue) = self.cursor_queue.disable_irq().lock().as_mut() {
+            cursor_queue.reclaim();
+        }
+    }
+
+    fn handle_config_change(&self) {
+        let events = self.config_manager.read_events();
+        debug!(
+            "Virtio GPU device config space change, events = {:#x}",
+            events
+        );
+        if events != 0 {
+            self.handle_events(events);
+        }
+        // The device notifies a configuration change when it needs a reset.
+        if self.needs_reset() {
+            warn!("Virtio GPU device needs a reset");
+        }
+    }
+
+    /// Handles the pending `events` and acknowledges them to the device.
+    ///
+    /// The device keeps signaling an event until it is cleared, so the events
+    /// are cleared even if they have no handler.
+    fn handle_events(&self, events: u32) {
+        let mut unhandled = events;
+        for &(event, handler) in EVENT_HANDLERS {
+            if events & event != 0 {
+                handler(self);
+                unhandled &= !event;
+            }
+        }
+        if unhandled != 0 {
+            warn!("Virtio GPU device signaled unknown events {:#x}", unhandled);
+        }
+        self.config_manager.clear_events(events);
+    }
+
+    /// Handles [`VIRTIO_GPU_EVENT_DISPLAY`], which the device signals when
+    /// the displays have changed.
+    fn handle_display_event(&self) {
+        *self.edids.disable_irq().lock() = [const { None }; VIRTIO_GPU_MAX_SCANOUTS];
+        *self.raw_edids.disable_irq().lock() = [const { None }; VIRTIO_GPU_MAX_SCANOUTS];
+        *self.display_info.disable_irq().lock() = None;
+        self.display_events.fetch_add(1, Ordering::Release);
+    }
+
+    // ==================== 2D commands ====================
+
+    /// Gets the information of all scanouts.
+    pub fn request_display_info(&self) -> Result<VirtioGpuRespDisplayInfo, VirtioDeviceError> {
+        let req = VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdGetDisplayInfo);
+        let resp: VirtioGpuRespDisplayInfo = self.request(&req)?;
+        check_response(&resp.hdr, VirtioGpuCtrlType::RespOkDisplayInfo)?;
+        Ok(resp)
+    }
+
+    /// Returns the information of all scanouts.
+    ///
+    /// The information is only requested from the device the first time and
+    /// after a display event, since the scanouts do not change otherwise.
+    pub fn display_info(&self) -> Result<VirtioGpuRespDisplayInfo, VirtioDeviceError> {
+        if let Some(display_info) = *self.display_info.disable_irq().lock() {
+            return Ok(display_info);
+        }
+
+        let display_info = self.request_display_info()?;
+        *self.display_info.disable_irq().lock() = Some(display_info);
+        Ok(display_info)
+    }
+
+    /// Returns the index and the preferred rectangle of each enabled scanout.
+    ///
+    /// The rectangles come from the cached display information (see
+    /// [`Self::display_info`]). Nothing is yielded if it cannot be requested.
+    pub fn scanouts(&self) -> impl Iterator<Item = (usize, VirtioGpuRect)> {
+        let display_info = self
+            .display_info()
+            .inspect_err(|err| warn!("virtio-gpu: failed to get the display info: {:?}", err))
+            .ok();
+        let num_scanouts = if display_info.is_some() {
+            self.config_num_scanouts() as usize
+        } else {
+            0
+        };
+        (0..num_scanouts).filter_map(move |index| {
+            let display_info = display_info.as_ref()?;
+            if !display_info.is_enabled(index) {
+                return None;
+            }
+            Some((index, display_info.get_rect(index)?))
+        })
+    }
+
+    /// Waits until a scanout is enabled with a nonzero size, and returns its
+    /// width and height.
+    ///
+    /// On a cold boot, the host may bring up its displays after the device is
+    /// initialized, and signals [`VIRTIO_GPU_EVENT_DISPLAY`] once they change.
+    /// The display information is requested again after each display event,
+    /// until none has arrived within `timeout`, which fails with
+    /// [`VirtioDeviceError::Timeout`].
+    pub fn wait_for_display(&self, timeout: Duration) -> Result<(u32, u32), VirtioDeviceError> {
+        let deadline = read_tsc().saturating_add(duration_to_cycles(timeout));
+        loop {
+            // The count is read first, so an event that arrives while the
+            // display information is requested is not missed.
+            let seen_events = self.display_events.load(Ordering::Acquire);
+            let display_info = self.display_info()?;
+            let size = (0..self.config_num_scanouts() as usize)
+                .filter(|&index| display_info.is_enabled(index))
+                .filter_map(|index| display_info.get_rect(index))
+                .find(|rect| rect.width() > 0 && rect.height() > 0);
+            if let Some(rect) = size {
+                return Ok((rect.width(), rect.height()));
+            }
+
+            while self.display_events.load(Ordering::Acquire) == seen_events {
+                if read_tsc() >= deadline {
+                    return Err(VirtioDeviceError::Timeout);
+                }
+                // The interrupt of the configuration change may not be
+                // delivered, e.g., if the caller has disabled interrupts.
+                let events = self.config_manager.read_events();
+                if events != 0 {
+                    self.handle_events(events);
+                }
+                spin_loop();
+            }
+        }
+    }
+
+    /// Logs the information of all scanouts of the device as a table.
+    pub fn log_display_info(&self) {
+        let display_info = match self.display_info() {
+            Ok(display_info) => display_info,
+            Err(err) => {
+                warn!("virtio-gpu: failed to get the display info: {:?}", err);
+                return;
+            }
+        };
+
+        let num_scanouts = self.config_num_scanouts() as usize;
+        info!("virtio-gpu: {} scanout(s)", num_scanouts);
+        info!(
+            "{:>7} {:>6} {:>6} {:>6} {:>6} {:>7}",
+            "scanout", "x", "y", "width", "height", "enabled"
+        );
+        for (index, pmode) in display_info.pmodes.iter().enumerate().take(num_scanouts) {
+            info!(
+                "{:>7} {:>6} {:>6} {:>6} {:>6} {:>7}",
+                index,
+                pmode.r.x(),
+                pmode.r.y(),
+                pmode.r.width(),
+                pmode.r.height(),
+                pmode.enabled != 0
+            );
+        }
+    }
+
+    /// Gets the EDID of a scanout from the device.
+    ///
+    /// The EDID blob is shorter than the response buffer, so only the bytes
+    /// returned by [`VirtioGpuRespEdid::edid`], as many as the `size` field
+    /// reports, are valid.
+    ///
+    /// Fails with [`VirtioDeviceError::FeatureNotSupported`] if
+    /// [`GPUFeatures::VIRTIO_GPU_F_EDID`] is not negotiated, and with
+    /// [`VirtioDeviceError::InvalidScanout`] if the scanout does not exist.
+    pub fn request_edid_info(
+        &self,
+        scanout_id: u32,
+    ) -> Result<VirtioGpuRespEdid, VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_EDID)?;
+        if scanout_id >= self.config_num_scanouts() {
+            return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+        }
+
+        let req = VirtioGpuGetEdid::new(scanout_id);
+        let resp: VirtioGpuRespEdid = self.request_sized(&req)?;
+        check_response(&resp.hdr, VirtioGpuCtrlType::RespOkEdid)?;
+        if resp.size as usize > VIRTIO_GPU_MAX_EDID_SIZE {
+            warn!(
+                "the EDID of scanout {} has {} bytes, truncated to {}",
+                scanout_id, resp.size, VIRTIO_GPU_MAX_EDID_SIZE
+            );
+        }
+        Ok(resp)
+    }
+
+    /// Returns the parsed EDID of a scanout.
+    ///
+    /// The EDID is requested from the device once and then cached until the
+    /// device signals a display change. Returns `Ok(None)` if the EDID from the
+    /// device is malformed. Fails for the same reasons as [`Self::request_edid_info`].
+    pub fn edid(&self, scanout_id: u32) -> Result<Option<Edid>, VirtioDeviceError> {
+        let cached = self
+            .edids
+            .disable_irq()
+            .lock()
+            .get(scanout_id as usize)
+            .cloned()
+            .flatten();
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let blob = self.edid_blob(scanout_id)?;
+        // Real monitors occasionally present malformed EDIDs, so the display
+        // info is used instead of failing.
+        let edid = Edid::parse(&blob)
+            .inspect_err(|err| {
+                warn!(
+                    "the EDID of scanout {} is malformed ({:?}), falling back to the display info",
+                    scanout_id, err
+                );
+            })
+            .ok();
+        self.edids.disable_irq().lock()[scanout_id as usize] = edid.clone();
+        Ok(edid)
+    }
+
+    /// Returns the EDID blob of a scanout unchanged, with as many bytes as the
+    /// device reports, e.g., for a service that does its own parsing.
+    ///
+    /// The blob is cached like [`Self::edid`], and is returned even if it is
+    /// malformed. Returns `None` if it cannot be requested (see
+    /// [`Self::request_edid_info`]).
+    pub fn raw_edid(&self, scanout_id: u32) -> Option<Vec<u8>> {
+        self.edid_blob(scanout_id).ok()
+    }
+
+    /// Returns the cached EDID blob of a scanout, requesting it if needed.
+    fn edid_blob(&self, scanout_id: u32) -> Result<Vec<u8>, VirtioDeviceError> {
+        let cached = self
+            .raw_edids
+            .disable_irq()
+            .lock()
+            .get(scanout_id as usize)
+            .cloned()
+            .flatten();
+        if let Some(blob) = cached {
+            return Ok(blob);
+        }
+
+        let blob = self.request_edid_info(scanout_id)?.edid().to_vec();
+        self.raw_edids.disable_irq().lock()[scanout_id as usize] = Some(blob.clone());
+        Ok(blob)
+    }
+
+    /// Returns the preferred resolution of a scanout from its EDID.
+    ///
+    /// Returns `None` if the device does not support EDID or the EDID is unusable,
+    /// in which case the resolution from the display information should be used.
+    fn preferred_resolution(&self, scanout_id: u32) -> Option<(u32, u32)> {
+        if !self.supports(GPUFeatures::VIRTIO_GPU_F_EDID) {
+            return None;
+        }
+
+        match self.edid(scanout_id) {
+            Ok(edid) => edid?.preferred_resolution(),
+            Err(err) => {
+                warn!(
+                    "failed to get the EDID of scanout {}: {:?}",
+                    scanout_id, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Creates a 2D resource on the host.
+    ///
+    /// Fails with [`VirtioDeviceError::HostOutOfMemory`] if the host cannot
+    /// allocate the resource, in which case the caller may release some of
+    /// its resources and try again.
+    pub fn resource_create_2d(
+        &self,
+        resource_id: u32,
+        format: VirtioGpuFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        let stride = width as usize * format.bytes_per_pixel();
+        self.resource_create_2d_with_stride(resource_id, format, width, height, stride)
+    }
+
+    /// Creates a 2D resource on the host, whose rows are `stride` bytes apart
+    /// in its guest backing storage.
+    ///
+    /// The stride may include padding, e.g., to align the rows to pages. The
+    /// transfers of the resource take it into account. Fails with
+    /// [`VirtioDeviceError::InvalidResource`] if `stride` is smaller than a row.
+    pub fn resource_create_2d_with_stride(
+        &self,
+        resource_id: u32,
+        format: VirtioGpuFormat,
+        width: u32,
+        height: u32,
+        stride: usize,
+    ) -> Result<(), VirtioDeviceError> {
+        if stride < width as usize * format.bytes_per_pixel() {
+            return Err(VirtioDeviceError::InvalidResource(resource_id));
+        }
+        self.check_resource_size(width, height)?;
+
+        let req = VirtioGpuResourceCreate2D::new(resource_id, format, width, height);
+        if let Err(err) = self.request_nodata(&req) {
+            if let VirtioDeviceError::HostOutOfMemory = err {
+                warn!(
+                    "the host is out of memory for the {}x{} resource {}",
+                    width, height, resource_id
+                );
+            }
+            return Err(err);
+        }
+        self.resources.disable_irq().lock().insert(
+            resource_id,
+            ResourceInfo::new(width, height, format, stride),
+        );
+        Ok(())
+    }
+
+    /// Creates a blob resource whose contents are its guest backing storage.
+    ///
+    /// The host displays the pixels of `backing` directly, so the resource
+    /// needs no [`Self::transfer_to_host_2d`] before [`Self::resource_flush`].
+    /// The pixels are stored row by row, `width` pixels of `format` apart.
+    ///
+    /// Fails with [`VirtioDeviceError::FeatureNotSupported`] if
+    /// [`GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB`] is not negotiated, and with
+    /// [`VirtioDeviceError::InvalidResource`] if `backing` is too small for
+    /// the pixels.
+    pub fn resource_create_blob(
+        &self,
+        resource_id: u32,
+        format: VirtioGpuFormat,
+        width: u32,
+        height: u32,
+        backing: &DmaStream,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB)?;
+        self.check_resource_size(width, height)?;
+
+        let size = width as u64 * height as u64 * format.bytes_per_pixel() as u64;
+        if (backing.nbytes() as u64) < size {
+            return Err(VirtioDeviceError::InvalidResource(resource_id));
+        }
+
+        let req = VirtioGpuResourceCreateBlob::new(
+            resource_id,
+            VIRTIO_GPU_BLOB_MEM_GUEST,
+            VIRTIO_GPU_BLOB_FLAG_USE_SHAREABLE,
+            1,
+            0,
+            size,
+        );
+        let entry = VirtioGpuMemEntry::new(backing.daddr() as u64, backing.nbytes() as u32);
+        let resp: VirtioGpuCtrlHdr = self.request_with_payload(&req, &[entry])?;
+        if let Err(err) = check_response(&resp, VirtioGpuCtrlType::RespOkNodata) {
+            if let VirtioDeviceError::HostOutOfMemory = err {
+                warn!(
+                    "the host is out of memory for the {}x{} blob resource {}",
+                    width, height, resource_id
+                );
+            }
+            return Err(err);
+        }
+
+        self.resources.disable_irq().lock().insert(
+            resource_id,
+            ResourceInfo::new_blob(width, height, format, backing.clone()),
+        );
+        Ok(())
+    }
+
+    /// Creates a blob resource of `size` bytes of host memory.
+    ///
+    /// The blob belongs to the 3D context `ctx_id`, and `blob_id` identifies the
+    /// memory within the context. With [`VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE`] in
+    /// `blob_flags`, the blob can be mapped with [`Self::resource_map_blob`].
+    ///
+    /// Fails with [`VirtioDeviceError::FeatureNotSupported`] if
+    /// [`GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB`] is not negotiated. Unlike
+    /// other resources, host blobs are not recreated by [`Self::reset`].
+    ///
+    /// [`VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE`]: super::control::VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE
+    pub fn resource_create_blob_host3d(
+        &self,
+        resource_id: u32,
+        ctx_id: u32,
+        blob_id: u64,
+        size: u64,
+        blob_flags: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB)?;
+
+        let req = VirtioGpuResourceCreateBlob::new(
+            resource_id,
+            VIRTIO_GPU_BLOB_MEM_HOST3D,
+            blob_flags,
+            0,
+            blob_id,
+            size,
+        )
+        .with_ctx_id(ctx_id);
+        self.request_nodata(&req)?;
+        self.host_blobs
+            .disable_irq()
+            .lock()
+            .insert(resource_id, size);
+        Ok(())
+    }
+
+    /// Returns the shared memory regions of the device.
+    ///
+    /// Host blobs are mapped in the region of [`VIRTIO_GPU_SHM_ID_HOST_VISIBLE`],
+    /// which is only used if [`GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB`] is
+    /// negotiated.
+    pub fn shm_regions(&self) -> Vec<ShmRegion> {
+        self.transport
+            .disable_irq()
+            .lock()
+            .shared_memory_regions()
+            .into_iter()
+            .map(|(id, io_mem)| ShmRegion {
+                id,
+                addr: io_mem.paddr(),
+                length: io_mem.length() as u64,
+            })
+            .collect()
+    }
+
+    /// Maps a blob of host memory at `offset` of the host-visible region and
+    /// returns the mapped memory.
+    ///
+    /// The guest accesses the host memory of the blob directly through the
+    /// returned memory, until the blob is unmapped by [`Self::resource_unmap_blob`].
+    ///
+    /// Fails with [`VirtioDeviceError::FeatureNotSupported`] if the device has
+    /// no host-visible region, and with [`VirtioDeviceError::InvalidResource`]
+    /// if the resource is not a blob created by [`Self::resource_create_blob_host3d`]
+    /// or does not fit in the region at `offset`.
+    pub fn resource_map_blob(
+        &self,
+        resource_id: u32,
+        offset: u64,
+    ) -> Result<IoMem, VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB)?;
+        let host_visible = self
+            .host_visible
+            .as_ref()
+            .ok_or(VirtioDeviceError::FeatureNotSupported)?;
+        let size = *self
+            .host_blobs
+            .disable_irq()
+            .lock()
+            .get(&resource_id)
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+        let end = offset
+            .checked_add(size)
+            .filter(|&end| end <= host_visible.length() as u64)
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+
+        let req = VirtioGpuResourceMapBlob::new(resource_id, offset);
+        let resp: VirtioGpuRespMapInfo = self.request(&req)?;
+        check_response(&resp.hdr, VirtioGpuCtrlType::RespOkMapInfo)?;
+        debug!(
+            "virtio gpu blob {} mapped at {:#x} with caching type {:#x}",
+            resource_id,
+            offset,
+            resp.map_info & VIRTIO_GPU_MAP_CACHE_MASK
+        );
+
+        Ok(host_visible.slice(offset as usize..end as usize))
+    }
+
+    /// Unmaps a blob mapped by [`Self::resource_map_blob`].
+    ///
+    /// The memory returned by the mapping must no longer be accessed.
+    pub fn resource_unmap_blob(&self, resource_id: u32) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB)?;
+
+        let req = VirtioGpuResourceUnmapBlob::new(resource_id);
+        self.request_nodata(&req)
+    }
+
+    /// Returns the formats of 2D resources that the host accepts.
+    ///
+    /// virtio-gpu has no command to query the formats, so the first call
+    /// probes them by creating and destroying a 1x1 resource of each format in
+    /// [`VirtioGpuFormat::ALL`]. The result is cached afterward.
+    pub fn supported_formats(&self) -> &[VirtioGpuFormat] {
+        self.supported_formats.call_once(|| {
+            VirtioGpuFormat::ALL
+                .into_iter()
+                .filter(|&format| self.probe_format(format))
+                .collect()
+        })
+    }
+
+    /// Returns whether the host can create a resource of `format`.
+    fn probe_format(&self, format: VirtioGpuFormat) -> bool {
+        let resource_id = self.alloc_resource_id();
+        if let Err(err) = self.resource_create_2d(resource_id, format, 1, 1) {
+            debug!("virtio gpu format {:?} is not supported: {:?}", format, err);
+            return false;
+        }
+        if let Err(err) = self.resource_unref(resource_id) {
+            warn!(
+                "failed to release the probing resource {}: {:?}",
+                resource_id, err
+            );
+        }
+        true
+    }
+
+    /// Returns the information of a 2D resource created by [`Self::resource_create_2d`]
+    /// or [`Self::resource_create_blob`].
+    pub fn resource_info(&self, resource_id: u32) -> Option<ResourceInfo> {
+        self.resources
+            .disable_irq()
+            .lock()
+            .get(&resource_id)
+            .cloned()
+    }
+
+    /// Attaches `backing` as the guest backing storage of a resource.
+    ///
+    /// If a 2D resource already has a backing storage, it is replaced, e.g.,
+    /// to switch between two buffers without recreating the resource. The old
+    /// one is detached and the new one attached with the control queue locked,
+    /// so no other command sees the resource in between.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidResource`] if the resource has
+    /// not been created by [`Self::resource_create_2d`] or
+    /// [`Self::resource_create_3d`], if `backing` is too small for the
+    /// pixels of a 2D resource, or if the backing storage of a blob resource
+    /// would be replaced. The layout of a 3D resource is up to its users, so
+    /// the size of its backing is not checked.
+    pub fn resource_attach_backing(
+        &self,
+        resource_id: u32,
+        backing: &DmaStream,
+    ) -> Result<(), VirtioDeviceError> {
+        let is_3d = self
+            .resources_3d
+            .disable_irq()
+            .lock()
+            .contains(&resource_id);
+        let mut replaces = false;
+        if !is_3d {
+            let info = self
+                .resource_info(resource_id)
+                .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+            if (backing.nbytes() as u64) < info.backing_size() {
+                warn!(
+                    "the {} bytes of backing are too small for the {}x{} resource {}",
+                    backing.nbytes(),
+                    info.width(),
+                    info.height(),
+                    resource_id
+                );
+                return Err(VirtioDeviceError::InvalidResource(resource_id));
+            }
+            replaces = info.backing().is_some();
+            // The host maps the backing of a blob when the blob is created.
+            if replaces && info.is_blob() {
+                return Err(VirtioDeviceError::InvalidResource(resource_id));
+            }
+        }
+        if replaces {
+            self.replace_backing(resource_id, backing)?;
+        } else {
+            let req = VirtioGpuResourceAttachBacking::new(resource_id, 1);
+            let entry = VirtioGpuMemEntry::new(backing.daddr() as u64, backing.nbytes() as u32);
+            let resp: VirtioGpuCtrlHdr = self.request_with_payload(&req, &[entry])?;
+            check_response(&resp, VirtioGpuCtrlType::RespOkNodata)?;
+        }
+
+        if let Some(info) = self.resources.disable_irq().lock().get_mut(&resource_id) {
+            info.set_backing(Some(backing.clone()));
+        }
+        Ok(())
+    }
+
+    /// Detaches the backing storage of a resource and attaches `backing`
+    /// instead, with the control queue locked in between.
+    fn replace_backing(
+        &self,
+        resource_id: u32,
+        backing: &DmaStream,
+    ) -> Result<(), VirtioDeviceError> {
+        let mut queue = self.lock_control_queue();
+        self.check_ready()?;
+
+        let req = VirtioGpuResourceDetachBacking::new(resource_id);
+        let req_slice = DmaSliceAllocator::new(&self.control_request).alloc_val(&req);
+        let resp: VirtioGpuCtrlHdr = self.request_locked(&mut queue, &req_slice, None)?;
+        check_response(&resp, VirtioGpuCtrlType::RespOkNodata)?;
+
+        let req = VirtioGpuResourceAttachBacking::new(resource_id, 1);
+        let entry = VirtioGpuMemEntry::new(backing.daddr() as u64, backing.nbytes() as u32);
+        let mut request_buf = DmaSliceAllocator::new(&self.control_request);
+        let req_slice = request_buf.alloc_val(&req);
+        let payload_slice = request_buf.alloc_slice(&[entry]);
+        let result = self
+            .request_locked(&mut queue, &req_slice, Some(&payload_slice))
+            .and_then(|resp: VirtioGpuCtrlHdr| {
+                check_response(&resp, VirtioGpuCtrlType::RespOkNodata)
+            });
+        if result.is_err() {
+            // The old backing storage is detached either way.
+            if let Some(info) = self.resources.disable_irq().lock().get_mut(&resource_id) {
+                info.set_backing(None);
+            }
+        }
+        result
+    }
+
+    /// Displays the `rect` area of an existing resource on a scanout.
+    ///
+    /// This switches the resource displayed on the scanout without creating a
+    /// new one. A `resource_id` of 0 disables the scanout.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidScanout`] if the scanout does not
+    /// exist, with [`VirtioDeviceError::InvalidResource`] if the resource has
+    /// not been created by [`Self::resource_create_2d`], and with
+    /// [`VirtioDeviceError::InvalidRect`] if `rect` is not within the resource
+    /// or the display of the scanout.
+    ///
+    /// If a border color is set with [`Self::set_scanout_border`] and `rect` is
+    /// smaller than the display, the display keeps its size and shows `rect` at
+    /// its position in the resource, with the border color around it. The
+    /// resource must then cover the display and have guest backing storage,
+    /// whose pixels around `rect` are overwritten.
+    pub fn attach_scanout(
+        &self,
+        scanout_id: u32,
+        resource_id: u32,
+        rect: VirtioGpuRect,
+    ) -> Result<(), VirtioDeviceError> {
+        if scanout_id >= self.config_num_scanouts() {
+            return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+        }
+        if resource_id == 0 {
+            return self.set_scanout(scanout_id, resource_id, rect);
+        }
+
+        // A display that reports no size has no bounds to check against.
+        let display = self.display_info()?.get_rect(scanout_id as usize).unwrap();
+        let display = VirtioGpuRect::new(0, 0, display.width(), display.height());
+        if display.width() == 0 || display.height() == 0 {
+            return self.set_scanout(scanout_id, resource_id, rect);
+        }
+        if !rect.is_within(&display) {
+            return Err(VirtioDeviceError::InvalidRect);
+        }
+
+        let border = *self.scanout_border.disable_irq().lock();
+        match border {
+            Some(color) if rect != display => {
+                self.draw_scanout_border(resource_id, &rect, &display, color)?;
+                self.set_scanout(scanout_id, resource_id, display)?;
+                self.resource_flush(display, resource_id)
+            }
+            _ => self.set_scanout(scanout_id, resource_id, rect),
+        }
+    }
+
+    /// Sets the `0xAARRGGBB` color shown around the areas that
+    /// [`Self::attach_scanout`] displays on part of a display.
+    ///
+    /// With `None`, which is the default, the scanout shows only the area, and
+    /// the host usually switches the display to its size.
+    pub fn set_scanout_border(&self, color: Option<u32>) {
+        *self.scanout_border.disable_irq().lock() = color;
+    }
+
+    /// Fills the `display` area of a resource around `rect` with `color`, and
+    /// copies it to the host.
+    fn draw_scanout_border(
+        &self,
+        resource_id: u32,
+        rect: &VirtioGpuRect,
+        display: &VirtioGpuRect,
+        color: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.check_resource_rect(resource_id, display)?;
+        let info = self
+            .resource_info(resource_id)
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+        let backing = info
+            .backing()
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+        let bytes_per_pixel = info.format().bytes_per_pixel();
+        let pixel = PixelFormat::from(info.format()).encode(color);
+
+        for band in border_rects(rect, display) {
+            let row = pixel.repeat(band.width() as usize);
+            for y in band.y()..band.y() + band.height() {
+                let offset = pixel_offset(band.x(), y, info.stride(), bytes_per_pixel)
+                    .ok_or(VirtioDeviceError::InvalidRect)?;
+                backing.write_bytes(offset, &row).unwrap();
+                backing.sync(offset..offset + row.len()).unwrap();
+            }
+            if !info.is_blob() {
+                let (offset, _) = rect_span(&band, info.stride(), info.format())
+                    .ok_or(VirtioDeviceError::InvalidRect)?;
+                self.transfer_to_host_2d(band, offset as u64, resource_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns a scanout off, which then displays no resource.
+    ///
+    /// The resource that the scanout displayed is kept, so it can be displayed
+    /// again with [`Self::attach_scanout`]. Fails with
+    /// [`VirtioDeviceError::InvalidScanout`] if the scanout does not exist.
+    pub fn disable_scanout(&self, scanout_id: u32) -> Result<(), VirtioDeviceError> {
+        self.attach_scanout(scanout_id, 0, VirtioGpuRect::default())
+    }
+
+    /// Returns the resource displayed on a scanout, or `None` if the scanout
+    /// is disabled or does not exist.
+    pub fn scanout_resource(&self, scanout_id: u32) -> Option<u32> {
+        Some(self.current_scanout(scanout_id)?.resource_id)
+    }
+
+    /// Returns the resource and its area displayed on a scanout, or `None` if
+    /// the scanout is disabled or does not exist.
+    ///
+    /// This is what the driver has set on the scanout, without asking the device.
+    pub fn current_scanout(&self, scanout_id: u32) -> Option<ScanoutState> {
+        let (resource_id, rect) = (*self
+            .scanouts
+            .disable_irq()
+            .lock()
+            .get(scanout_id as usize)?)?;
+        Some(ScanoutState { resource_id, rect })
+    }
+
+    /// Displays the `rect` area of a resource on a scanout.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidScanout`] if the host rejects
+    /// the scanout.
+    fn set_scanout(
+        &self,
+        scanout_id: u32,
+        resource_id: u32,
+        rect: VirtioGpuRect,
+    ) -> Result<(), VirtioDeviceError> {
+        let blob = if resource_id != 0 {
+            self.check_resource_rect(resource_id, &rect)?;
+            self.resource_info(resource_id)
+                .filter(|info| info.is_blob())
+        } else {
+            None
+        };
+
+        // Blob resources have no layout on the host, so the scanout must be
+        // told how the pixels are laid out in the blob.
+        let result = match blob {
+            Some(info) => {
+                let stride = info.width() * info.format().bytes_per_pixel() as u32;
+                let req = VirtioGpuSetScanoutBlob::new(
+                    scanout_id,
+                    resource_id,
+                    rect,
+                    info.width(),
+                    info.height(),
+                    info.format(),
+                    stride,
+                    0,
+                );
+                self.request_nodata(&req)
+            }
+            None => {
+                let req = VirtioGpuSetScanout::new(scanout_id, resource_id, rect);
+                self.request_nodata(&req)
+            }
+        };
+        result.map_err(|err| match err {
+            VirtioDeviceError::ResponseError(resp_type)
+                if resp_type == VirtioGpuCtrlType::RespErrInvalidScanoutId as u32 =>
+            {
+                VirtioDeviceError::InvalidScanout(scanout_id)
+            }
+            err => err,
+        })?;
+
+        if let Some(displayed) = self
+            .scanouts
+            .disable_irq()
+            .lock()
+            .get_mut(scanout_id as usize)
+        {
+            *displayed = (resource_id != 0).then_some((resource_id, rect));
+        }
+        Ok(())
+    }
+
+    /// Assigns a UUID to a resource, by which other virtio devices can refer to
+    /// the same host resource.
+    ///
+    /// Fails with [`VirtioDeviceError::FeatureNotSupported`] if
+    /// [`GPUFeatures::VIRTIO_GPU_F_RESOURCE_UUID`] is not negotiated.
+    pub fn assign_resource_uuid(&self, resource_id: u32) -> Result<[u8; 16], VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_RESOURCE_UUID)?;
+
+        let req = VirtioGpuResourceAssignUuid::new(resource_id);
+        let resp: VirtioGpuRespResourceUuid = self.request(&req)?;
+        check_response(&resp.hdr, VirtioGpuCtrlType::RespOkResourceUuid)?;
+        Ok(resp.uuid)
+    }
+
+    /// Destroys a resource on the host.
+    pub fn resource_unref(&self, resource_id: u32) -> Result<(), VirtioDeviceError> {
+        let req = VirtioGpuResourceUnref::new(resource_id);
+        self.request_nodata(&req)?;
+        self.resources.disable_irq().lock().remove(&resource_id);
+        self.host_blobs.disable_irq().lock().remove(&resource_id);
+        self.resources_3d.disable_irq().lock().remove(&resource_id);
+        Ok(())
+    }
+
+    /// Detaches the guest backing storage from a resource.
+    ///
+    /// The device no longer accesses the backing storage after this returns.
+    pub fn resource_detach_backing(&self, resource_id: u32) -> Result<(), VirtioDeviceError> {
+        let req = VirtioGpuResourceDetachBacking::new(resource_id);
+        self.request_nodata(&req)?;
+        if let Some(info) = self.resources.disable_irq().lock().get_mut(&resource_id) {
+            info.set_backing(None);
+        }
+        Ok(())
+    }
+
+    /// Copies the `rect` area of a resource from its guest backing storage to the host.
+    ///
+    /// Blob resources need no transfers, since the host uses their backing
+    /// storage directly.
+    ///
+    /// `offset` is the offset of the first pixel of `rect` in the backing storage.
+    /// The host assumes packed rows, so the rows of a resource created with a
+    /// padded stride are transferred one by one, in a single batch.
+    pub fn transfer_to_host_2d(
+        &self,
+        rect: VirtioGpuRect,
+        offset: u64,
+        resource_id: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.check_resource_rect(resource_id, &rect)?;
+        let info = self
+            .resource_info(resource_id)
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+        trace_transfer(resource_id, &rect, info.format());
+
+        if info.is_packed() || rect.height() <= 1 {
+            let req = VirtioGpuTransferToHost2D::new(rect, offset, resource_id);
+            return self.request_nodata(&req);
+        }
+
+        self.batch(|batch| {
+            for row in 0..rect.height() {
+                let row_rect = VirtioGpuRect::new(rect.x(), rect.y() + row, rect.width(), 1);
+                let row_offset = (row as u64)
+                    .checked_mul(info.stride() as u64)
+                    .and_then(|row_offset| row_offset.checked_add(offset))
+                    .ok_or(VirtioDeviceError::InvalidRect)?;
+                batch.transfer_to_host_2d(row_rect, row_offset, resource_id);
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies the `rect` area of a resource from the host to its guest backing storage.
+    ///
+    /// virtio-gpu has no 2D command for host-to-guest transfers, so this issues
+    /// `VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D` with a one-layer box, which
+    /// requires [`GPUFeatures::VIRTIO_GPU_F_VIRGL`]. Without the feature, this
+    /// fails with [`VirtioDeviceError::FeatureNotSupported`].
+    ///
+    /// `offset` is the offset of the first pixel of `rect` in the backing storage.
+    /// If the resource is the framebuffer, its backing storage is synced afterward;
+    /// otherwise, the caller must sync the backing storage before reading it.
+    pub fn transfer_from_host_2d(
+        &self,
+        rect: VirtioGpuRect,
+        offset: u64,
+        resource_id: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+        self.check_resource_rect(resource_id, &rect)?;
+        let info = self
+            .resource_info(resource_id)
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+
+        // Unlike the 2D transfer, the 3D one can be told the stride of the rows.
+        let stride = if info.is_packed() {
+            0
+        } else {
+            info.stride() as u32
+        };
+        let box_ = VirtioGpuBox::new(rect.x(), rect.y(), 0, rect.width(), rect.height(), 1);
+        let req = VirtioGpuTransferHost3D::new_from_host(box_, offset, resource_id, 0, stride, 0);
+        self.request_nodata(&req)?;
+
+        let framebuffer = self.framebuffer.disable_irq().lock();
+        if let Some(framebuffer) = framebuffer
+            .as_ref()
+            .filter(|framebuffer| framebuffer.resource_id == resource_id)
+        {
+            let span = rect_span(&rect, framebuffer.host_stride(), framebuffer.format);
+            let range = span
+                .and_then(|(_, len)| Some(offset as usize..(offset as usize).checked_add(len)?))
+                .ok_or(VirtioDeviceError::InvalidRect)?;
+            framebuffer.host_backing().sync(range).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the `rect` area of a resource to the scanouts that display it.
+    pub fn resource_flush(
+        &self,
+        rect: VirtioGpuRect,
+        resource_id: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.check_resource_rect(resource_id, &rect)?;
+
+        let req = VirtioGpuResourceFlush::new(rect, resource_id);
+        self.request_nodata(&req)
+    }
+
+    /// Checks that the resource has been created by [`Self::resource_create_2d`]
+    /// and that `rect` lies within it.
+    fn check_resource_rect(
+        &self,
+        resource_id: u32,
+        rect: &VirtioGpuRect,
+    ) -> Result<(), VirtioDeviceError> {
+        let resources = self.resources.disable_irq().lock();
+        let info = resources
+            .get(&resource_id)
+            .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+        if info.contains(rect) {
+            Ok(())
+        } else {
+            Err(VirtioDeviceError::InvalidRect)
+        }
+    }
+
+    // ==================== 3D commands ====================
+    //
+    // All of them fail with `VirtioDeviceError::FeatureNotSupported` if
+    // `GPUFeatures::VIRTIO_GPU_F_VIRGL` is not negotiated.
+
+    /// Gets the information of the capability set at `capset_index`, which
+    /// is below [`Self::config_num_capsets`].
+    pub fn get_capset_info(
+        &self,
+        capset_index: u32,
+    ) -> Result<VirtioGpuRespCapsetInfo, VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let req = VirtioGpuGetCapsetInfo::new(capset_index);
+        let resp: VirtioGpuRespCapsetInfo = self.request(&req)?;
+        check_response(&resp.hdr, VirtioGpuCtrlType::RespOkCapsetInfo)?;
+        Ok(resp)
+    }
+
+    /// Returns the id and the maximum version of each capability set of the
+    /// device, e.g., to choose the context type of [`Self::ctx_create`].
+    ///
+    /// The capability sets are probed with [`Self::get_capset_info`] the
+    /// first time, and cached if none of the probes has failed. There are none
+    /// without [`GPUFeatures::VIRTIO_GPU_F_VIRGL`].
+    pub fn capsets(&self) -> Vec<(u32, u32)> {
+        if let Some(capsets) = self.capsets.disable_irq().lock().as_ref() {
+            return capsets.clone();
+        }
+        if !self.supports(GPUFeatures::VIRTIO_GPU_F_VIRGL) {
+            return Vec::new();
+        }
+
+        let mut complete = true;
+        let capsets: Vec<_> = (0..self.config_num_capsets())
+            .filter_map(|index| match self.get_capset_info(index) {
+                Ok(info) => Some((info.capset_id, info.capset_max_version)),
+                Err(err) => {
+                    warn!(
+                        "virtio gpu failed to get the info of capset {}: {:?}",
+                        index, err
+                    );
+                    complete = false;
+                    None
+                }
+            })
+            .collect();
+        if complete {
+            *self.capsets.disable_irq().lock() = Some(capsets.clone());
+        }
+        capsets
+    }
+
+    /// Returns whether the host can actually render 3D.
+    ///
+    /// The host may negotiate [`GPUFeatures::VIRTIO_GPU_F_VIRGL`] without
+    /// having a working renderer, so the first call also checks that the
+    /// device reports a capability set and answers [`Self::get_capset_info`]
+    /// for it. The result is cached afterward.
+    pub fn has_3d(&self) -> bool {
+        *self.has_3d.call_once(|| {
+            if !self.supports(GPUFeatures::VIRTIO_GPU_F_VIRGL) {
+                return false;
+            }
+            if self.config_num_capsets() == 0 {
+                debug!("virtio gpu negotiated virgl but reports no capsets");
+                return false;
+            }
+            match self.get_capset_info(0) {
+                Ok(info) => {
+                    debug!(
+                        "virtio gpu capset {} has version {} and size {}",
+                        info.capset_id, info.capset_max_version, info.capset_max_size
+                    );
+                    info.capset_max_size != 0
+                }
+                Err(err) => {
+                    warn!("virtio gpu failed to get capset info: {:?}", err);
+                    false
+                }
+            }
+        })
+    }
+
+    /// Creates a rendering context.
+    ///
+    /// `name` is only used by the host for debugging purposes. `context_init`
+    /// carries the capset id of the context type in its
+    /// [`VIRTIO_GPU_CONTEXT_INIT_CAPSET_ID_MASK`] bits, and requires
+    /// `VIRTIO_GPU_F_CONTEXT_INIT` unless it is zero. A zero `context_init`
+    /// creates a context of the default (virgl) type.
+    ///
+    /// [`VIRTIO_GPU_CONTEXT_INIT_CAPSET_ID_MASK`]: super::control::VIRTIO_GPU_CONTEXT_INIT_CAPSET_ID_MASK
+    pub fn ctx_create(
+        &self,
+        ctx_id: u32,
+        name: &str,
+        context_init: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let mut req = VirtioGpuCtxCreate::new(ctx_id, name);
+        if context_init != 0 {
+            self.require(GPUFeatures::VIRTIO_GPU_F_CONTEXT_INIT)?;
+            req = req.with_context_init(context_init);
+        }
+        self.request_nodata(&req)
+    }
+
+    /// Destroys a virgl rendering context.
+    pub fn ctx_destroy(&self, ctx_id: u32) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let req = VirtioGpuCtxDestroy::new(ctx_id);
+        self.request_nodata(&req)
+    }
+
+    /// Makes a resource accessible to the commands of a rendering context.
+    pub fn ctx_attach_resource(
+        &self,
+        ctx_id: u32,
+        resource_id: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let req = VirtioGpuCtxResource::new_attach(ctx_id, resource_id);
+        self.request_nodata(&req)
+    }
+
+    /// Makes a resource inaccessible to the commands of a rendering context.
+    pub fn ctx_detach_resource(
+        &self,
+        ctx_id: u32,
+        resource_id: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let req = VirtioGpuCtxResource::new_detach(ctx_id, resource_id);
+        self.request_nodata(&req)
+    }
+
+    /// Creates a 3D resource, e.g., a texture or a vertex buffer, on the host.
+    ///
+    /// The resource is only usable by the rendering contexts that it is
+    /// attached to with [`Self::ctx_attach_resource`]. Its contents are
+    /// uploaded from guest backing storage attached with
+    /// [`Self::resource_attach_backing`], see [`Self::transfer_to_host_3d`].
+    /// Unlike 2D resources, 3D resources are not recreated by [`Self::reset`].
+    pub fn resource_create_3d(&self, params: Resource3dParams) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let req = VirtioGpuResourceCreate3D::new(&params);
+        if let Err(err) = self.request_nodata(&req) {
+            if let VirtioDeviceError::HostOutOfMemory = err {
+                warn!(
+                    "the host is out of memory for the {}x{}x{} 3D resource {}",
+                    params.width, params.height, params.depth, params.resource_id
+                );
+            }
+            return Err(err);
+        }
+        self.resources_3d
+            .disable_irq()
+            .lock()
+            .insert(params.resource_id);
+        Ok(())
+    }
+
+    /// Copies an area of a 3D resource from its guest backing storage to the host.
+    ///
+    /// The host performs the transfer in the rendering context `ctx_id`, to
+    /// which the resource must be attached. The backing storage is read through
+    /// the memory entries attached with [`Self::resource_attach_backing`], so
+    /// large uploads need not fit in the request buffer.
+    pub fn transfer_to_host_3d(
+        &self,
+        ctx_id: u32,
+        params: Transfer3dParams,
+    ) -> Result<(), VirtioDeviceError> {
+        self.transfer_3d(VirtioGpuCtrlType::CmdTransferToHost3d, ctx_id, &params)
+    }
+
+    /// Copies an area of a 3D resource from the host to its guest backing storage.
+    ///
+    /// See [`Self::transfer_to_host_3d`]. The caller must sync the backing
+    /// storage before reading it.
+    pub fn transfer_from_host_3d(
+        &self,
+        ctx_id: u32,
+        params: Transfer3dParams,
+    ) -> Result<(), VirtioDeviceError> {
+        self.transfer_3d(VirtioGpuCtrlType::CmdTransferFromHost3d, ctx_id, &params)
+    }
+
+    fn transfer_3d(
+        &self,
+        type_: VirtioGpuCtrlType,
+        ctx_id: u32,
+        params: &Transfer3dParams,
+    ) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+        if !self
+            .resources_3d
+            .disable_irq()
+            .lock()
+            .contains(&params.resource_id)
+        {
+            return Err(VirtioDeviceError::InvalidResource(params.resource_id));
+        }
+
+        let req = VirtioGpuTransferHost3D::new(type_, params).with_ctx_id(ctx_id);
+        self.request_nodata(&req)
+    }
+
+    /// Submits a virgl command buffer to a rendering context.
+    ///
+    /// The commands are only queued on the host. Use [`Self::submit_3d_fenced`]
+    /// to wait until the host has finished executing them.
+    pub fn submit_3d(&self, ctx_id: u32, commands: &[u8]) -> Result<(), VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let req = VirtioGpuCmdSubmit::new(ctx_id, commands.len() as u32);
+        self.submit_3d_with(&req, commands)
+    }
+
+    /// Submits a virgl command buffer to a rendering context with a fence.
+    ///
+    /// The device completes a fenced command only after the host has finished
+    /// executing it, so the commands are done when this method returns.
+    /// Returns the id of the fence.
+    pub fn submit_3d_fenced(&self, ctx_id: u32, commands: &[u8]) -> Result<u64, VirtioDeviceError> {
+        self.require(GPUFeatures::VIRTIO_GPU_F_VIRGL)?;
+
+        let fence_id = self.alloc_fence_id();
+        let req = VirtioGpuCmdSubmit::new(ctx_id, commands.len() as u32).with_fence(fence_id);
+        self.submit_3d_with(&req, commands)?;
+        Ok(fence_id)
+    }
+
+    fn submit_3d_with(
+        &self,
+        req: &VirtioGpuCmdSubmit,
+        commands: &[u8],
+    ) -> Result<(), VirtioDeviceError> {
+        if commands.is_empty() {
+            return self.request_nodata(req);
+        }
+
+        // The command buffer can be much larger than the shared request buffer,
+        // so it is copied to a DMA buffer of its own.
+        let commands_buf = alloc_dma_stream(commands.len(), DmaDirection::ToDevice)?;
+        let commands_slice = DmaStreamSlice::new(&commands_buf, 0, commands.len());
+        commands_slice.write_bytes(0, commands).unwrap();
+        commands_slice.sync().unwrap();
+
+        let resp: VirtioGpuCtrlHdr = self.request_with_dma_payload(req, &commands_slice)?;
+        check_response(&resp, VirtioGpuCtrlType::RespOkNodata)
+    }
+
+    // ==================== Framebuffer ====================
+
+    /// Sets up a framebuffer that covers the whole of a scanout.
+    ///
+    /// If `scanout_id` is `None`, the first enabled scanout is used, or scanout 0
+    /// if none is enabled. Otherwise, the scanout must exist and be enabled. A
+    /// scanout that reports no size gets a framebuffer of 1024x768.
+    /// Each scanout can have a framebuffer of its own, e.g., for a console on
+    /// every display.
+    ///
+    /// Draw on the framebuffer through [`FramebufferResource::lock`]. The contents
+    /// of the framebuffer become visible after flushing them with the guard.
+    /// The host resource of the framebuffer is released when the returned
+    /// [`FramebufferResource`] is dropped.
+    pub fn setup_framebuffer(
+        self: &Arc<Self>,
+        scanout_id: Option<u32>,
+    ) -> Result<FramebufferResource, VirtioDeviceError> {
+        let display_info = self.display_info()?;
+        let num_scanouts = self.config_num_scanouts();
+        let scanout_id = match scanout_id {
+            Some(scanout_id) => {
+                if scanout_id >= num_scanouts || !display_info.is_enabled(scanout_id as usize) {
+                    return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+                }
+                scanout_id
+            }
+            None => (0..num_scanouts)
+                .find(|&index| display_info.is_enabled(index as usize))
+                .unwrap_or(0),
+        };
+
+        let mut rect = display_info.get_rect(scanout_id as usize).unwrap();
+        debug!("virtio gpu scanout {} rect = {:?}", scanout_id, rect);
+        if let Some((width, height)) = self.preferred_resolution(scanout_id) {
+            debug!(
+                "virtio gpu scanout {} preferred resolution = {}x{}",
+                scanout_id, width, height
+            );
+            rect = VirtioGpuRect::new(rect.x(), rect.y(), width, height);
+        }
+        if rect.width() == 0 || rect.height() == 0 {
+            // No display is attached yet, but a display event may attach one
+            // later. The framebuffer keeps the fallback size until it is set up
+            // again.
+            let (width, height) = FALLBACK_RESOLUTION;
+            warn!(
+                "virtio gpu scanout {} reports no size, using {}x{}",
+                scanout_id, width, height
+            );
+            rect = VirtioGpuRect::new(rect.x(), rect.y(), width, height);
+        }
+        let (max_width, max_height) = self.max_resolution();
+        if rect.width() > max_width || rect.height() > max_height {
+            warn!(
+                "virtio gpu scanout {} is {}x{}, larger than the maximum {}x{}",
+                scanout_id,
+                rect.width(),
+                rect.height(),
+                max_width,
+                max_height
+            );
+            rect = VirtioGpuRect::new(
+                rect.x(),
+                rect.y(),
+                rect.width().min(max_width),
+                rect.height().min(max_height),
+            );
+        }
+
+        self.create_framebuffer(scanout_id, rect, FRAMEBUFFER_FORMAT, true)
+    }
+
+    /// Sets up a framebuffer of the given size and format on a scanout,
+    /// regardless of the resolution that the scanout reports.
+    ///
+    /// This forces a mode on hosts that report a conservative default one.
+    /// The scanout must exist, and the size must not exceed
+    /// [`Self::max_resolution`]. Otherwise, this is like
+    /// [`Self::setup_framebuffer`], except that no smaller framebuffer is
+    /// tried if the guest is out of memory.
+    pub fn setup_framebuffer_with(
+        self: &Arc<Self>,
+        scanout_id: u32,
+        width: u32,
+        height: u32,
+        format: VirtioGpuFormat,
+    ) -> Result<FramebufferResource, VirtioDeviceError> {
+        let display_info = self.display_info()?;
+        if scanout_id >= self.config_num_scanouts() {
+            return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+        }
+        self.check_resource_size(width, height)?;
+
+        let rect = display_info.get_rect(scanout_id as usize).unwrap();
+        let rect = VirtioGpuRect::new(rect.x(), rect.y(), width, height);
+        self.create_framebuffer(scanout_id, rect, format, false)
+    }
+
+    /// Sets up a framebuffer whose guest backing storage is `backing`, and
+    /// displays it on a scanout.
+    ///
+    /// This suits a buffer that the caller already owns, e.g., one shared with
+    /// another device, which saves allocating and copying another one. The
+    /// pixels are stored row by row in `backing`, with packed rows. The
+    /// backing storage is detached from the host resource when the
+    /// framebuffer is dropped, but stays alive as long as the caller holds it.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidResource`] with a zero resource
+    /// id if `backing` is too small for the framebuffer, and otherwise like
+    /// [`Self::setup_framebuffer_with`].
+    pub fn attach_external_framebuffer(
+        self: &Arc<Self>,
+        scanout_id: u32,
+        backing: DmaStream,
+        width: u32,
+        height: u32,
+        format: VirtioGpuFormat,
+    ) -> Result<FramebufferResource, VirtioDeviceError> {
+        let display_info = self.display_info()?;
+        if scanout_id >= self.config_num_scanouts() {
+            return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+        }
+        self.check_resource_size(width, height)?;
+        let size = framebuffer_size(width, height, format)?;
+        if backing.nbytes() < size {
+            warn!(
+                "the {} bytes of the external backing are too small for a {}x{} framebuffer",
+                backing.nbytes(),
+                width,
+                height
+            );
+            return Err(VirtioDeviceError::InvalidResource(0));
+        }
+
+        let rect = display_info.get_rect(scanout_id as usize).unwrap();
+        let rect = VirtioGpuRect::new(rect.x(), rect.y(), width, height);
+        let rotation = *self.rotation.disable_irq().lock();
+        self.display_framebuffer(scanout_id, rect, format, backing, rotation)
+    }
+
+    /// Creates a framebuffer of the size of `rect` and displays it on a scanout.
+    ///
+    /// If `fall_back` is true and the guest is out of memory for the
+    /// framebuffer, a smaller one is created instead.
+    fn create_framebuffer(
+        self: &Arc<Self>,
+        scanout_id: u32,
+        rect: VirtioGpuRect,
+        format: VirtioGpuFormat,
+        fall_back: bool,
+    ) -> Result<FramebufferResource, VirtioDeviceError> {
+        // The framebuffer is drawn upright, so it has the size of the scanout
+        // before the rotation.
+        let rotation = *self.rotation.disable_irq().lock();
+        let (rect, backing) =
+            alloc_framebuffer_backing(scanout_id, rotation.rotate_size(rect), format, fall_back)?;
+        self.display_framebuffer(scanout_id, rect, format, backing, rotation)
+    }
+
+    /// Creates the host resource of a framebuffer of the size of `rect`, whose
+    /// guest backing storage is `backing`, and displays it on a scanout.
+    fn display_framebuffer(
+        self: &Arc<Self>,
+        scanout_id: u32,
+        rect: VirtioGpuRect,
+        format: VirtioGpuFormat,
+        backing: DmaStream,
+        rotation: Rotation,
+    ) -> Result<FramebufferResource, VirtioDeviceError> {
+        let full_rect = VirtioGpuRect::new(0, 0, rect.width(), rect.height());
+        let host_rect = rotation.rotate_size(full_rect);
+        let rotated_backing = if rotation == Rotation::Deg0 {
+            None
+        } else {
+            let size = framebuffer_size(rect.width(), rect.height(), format)?;
+            Some(alloc_dma_stream(size, DmaDirection::Bidirectional)?)
+        };
+
+        // With blob resources, the host displays the backing directly, which
+        // saves copying the pixels on every flush. The software cursor and the
+        // rotation cannot be used with them, since they only apply to the copies.
+        let blob = self.supports(GPUFeatures::VIRTIO_GPU_F_RESOURCE_BLOB)
+            && self.has_hardware_cursor()
+            && rotated_backing.is_none();
+        let resource_id = self.alloc_resource_id();
+        if blob {
+            self.resource_create_blob(
+                resource_id,
+                format,
+                host_rect.width(),
+                host_rect.height(),
+                &backing,
+            )?;
+        } else {
+            self.resource_create_2d(resource_id, format, host_rect.width(), host_rect.height())?;
+        }
+        // From now on, the resource is released on errors by dropping the guard.
+        let mut framebuffer = FramebufferResource {
+            device: Arc::downgrade(self),
+            scanout_id: Some(scanout_id),
+            framebuffer: Framebuffer {
+                resource_id,
+                rect: full_rect,
+                format,
+                backing,
+                blob,
+                rotation,
+                rotated_backing,
+                offscreen: false,
+                draw_lock: Arc::new(Mutex::new(())),
+            },
+            shadow: None,
+        };
+
+        if !blob {
+            self.resource_attach_backing(resource_id, framebuffer.framebuffer.host_backing())?;
+        }
+        match self.set_scanout(scanout_id, resource_id, host_rect) {
+            Ok(()) => {}
+            // The host may disagree with the display info, so the framebuffer is
+            // displayed on another scanout instead of not at all.
+            Err(VirtioDeviceError::InvalidScanout(_)) => {
+                let display_info = self.display_info()?;
+                let num_scanouts = self.config_num_scanouts();
+                let fallback = (0..num_scanouts)
+                    .find(|&index| index != scanout_id && display_info.is_enabled(index as usize))
+                    .unwrap_or(0);
+                if fallback == scanout_id {
+                    return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+                }
+                warn!(
+                    "the host rejected virtio gpu scanout {}, falling back to scanout {}",
+                    scanout_id, fallback
+                );
+                self.set_scanout(fallback, resource_id, host_rect)?;
+                framebuffer.scanout_id = Some(fallback);
+            }
+            Err(err) => return Err(err),
+        }
+
+        *self.framebuffer.disable_irq().lock() = Some(framebuffer.framebuffer.clone());
+        let displayed = framebuffer.scanout_id.unwrap() as usize;
+        if let Some(scanout) = self
+            .scanout_framebuffers
+            .disable_irq()
+            .lock()
+            .get_mut(displayed)
+        {
+            *scanout = Some(framebuffer.framebuffer.clone());
+        }
+
+        Ok(framebuffer)
+    }
+
+    /// Sets up a framebuffer that is not displayed on any scanout.
+    ///
+    /// This suits rendering whose results are read back, e.g., with
+    /// [`FramebufferResource::capture`], or composited elsewhere. Flushing the
+    /// framebuffer only copies its contents to the host resource. The current
+    /// framebuffer of the device is left as it is.
+    pub fn setup_offscreen(
+        self: &Arc<Self>,
+        width: u32,
+        height: u32,
+        format: VirtioGpuFormat,
+    ) -> Result<FramebufferResource, VirtioDeviceError> {
+        self.check_resource_size(width, height)?;
+        let size = framebuffer_size(width, height, format)?;
+        let backing = alloc_dma_stream(size, DmaDirection::Bidirectional)?;
+        let resource_id = self.alloc_resource_id();
+        self.resource_create_2d(resource_id, format, width, height)?;
+        // From now on, the resource is released on errors by dropping the guard.
+        let framebuffer = FramebufferResource {
+            device: Arc::downgrade(self),
+            scanout_id: None,
+            framebuffer: Framebuffer {
+                resource_id,
+                rect: VirtioGpuRect::new(0, 0, width, height),
+                format,
+                backing,
+                blob: false,
+                rotation: Rotation::Deg0,
+                rotated_backing: None,
+                offscreen: true,
+                draw_lock: Arc::new(Mutex::new(())),
+            },
+            shadow: None,
+        };
+        self.resource_attach_backing(resource_id, framebuffer.backing())?;
+
+        Ok(framebuffer)
+    }
+
+    /// Makes the whole framebuffer visible on the display.
+    ///
+    /// This does not wait for the threads drawing on the framebuffer; use
+    /// [`FramebufferGuard::flush`] to flush after a complete drawing.
+    pub fn flush(&self) -> Result<(), VirtioDeviceError> {
+        let rect = match self.framebuffer.disable_irq().lock().as_ref() {
+            Some(framebuffer) => framebuffer.rect,
+            None => return Ok(()),
+        };
+        self.flush_rect(rect)
+    }
+
+    /// Makes the `rect` area of the framebuffer visible on the display.
+    pub fn flush_rect(&self, rect: VirtioGpuRect) -> Result<(), VirtioDeviceError> {
+        let Some(framebuffer) = self.current_framebuffer() else {
+            return Ok(());
+        };
+        self.flush_framebuffer(&framebuffer, rect, false)
+    }
+
+    /// Makes several areas of the framebuffer visible on the display.
+    ///
+    /// The areas are transferred and flushed one by one, but the commands are
+    /// submitted as a batch (see [`Self::batch`]), so scattered areas
+    /// cost neither a notification each nor the flush of their bounding box.
+    /// The parts of the areas outside of the framebuffer are ignored.
+    pub fn flush_rects(&self, rects: &[VirtioGpuRect]) -> Result<(), VirtioDeviceError> {
+        let Some(framebuffer) = self.current_framebuffer() else {
+            return Ok(());
+        };
+
+        self.batch(|batch| {
+            for rect in rects {
+                if let Some(rect) = clip_rect(rect, &framebuffer.rect) {
+                    batch.flush_rect(rect);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Makes the whole framebuffer displayed on a scanout visible.
+    ///
+    /// Each scanout can display its own framebuffer, set up by calling
+    /// [`Self::setup_framebuffer`] for it, while [`Self::flush`] only flushes
+    /// the framebuffer set up last. Does nothing if the scanout displays no
+    /// framebuffer, and fails with [`VirtioDeviceError::InvalidScanout`] if it
+    /// does not exist.
+    pub fn flush_scanout(&self, scanout_id: u32) -> Result<(), VirtioDeviceError> {
+        let Some(framebuffer) = self.scanout_framebuffer(scanout_id)? else {
+            return Ok(());
+        };
+        self.flush_framebuffer(&framebuffer, framebuffer.rect, false)
+    }
+
+    /// Makes the `rect` area of the framebuffer displayed on a scanout visible,
+    /// like [`Self::flush_scanout`].
+    pub fn flush_scanout_rect(
+        &self,
+        scanout_id: u32,
+        rect: VirtioGpuRect,
+    ) -> Result<(), VirtioDeviceError> {
+        let Some(framebuffer) = self.scanout_framebuffer(scanout_id)? else {
+            return Ok(());
+        };
+        self.flush_framebuffer(&framebuffer, rect, false)
+    }
+
+    /// Makes the whole framebuffer visible and waits until the host has presented it.
+    ///
+    /// The flush is fenced, so the device completes it only after the host has
+    /// consumed the frame. Animation loops can call this once per frame to
+    /// pace themselves to the host instead of spinning. Returns the fence id
+    /// of the frame, or `None` if there is no framebuffer.
+    ///
+    /// Like [`Self::flush`], this does not wait for the threads drawing on the
+    /// framebuffer.
+    pub fn present_and_wait(&self) -> Result<Option<u64>, VirtioDeviceError> {
+        let Some(framebuffer) = self.current_framebuffer() else {
+            return Ok(None);
+        };
+        self.present_framebuffer_and_wait(&framebuffer).map(Some)
+    }
+
+    /// Makes the whole framebuffer displayed on a scanout visible and waits
+    /// until the host has presented it, like [`Self::present_and_wait`].
+    ///
+    /// Returns `None` if the scanout displays no framebuffer, and fails with
+    /// [`VirtioDeviceError::InvalidScanout`] if it does not exist.
+    pub fn present_scanout_and_wait(
+        &self,
+        scanout_id: u32,
+    ) -> Result<Option<u64>, VirtioDeviceError> {
+        let Some(framebuffer) = self.scanout_framebuffer(scanout_id)? else {
+            return Ok(None);
+        };
+        self.present_framebuffer_and_wait(&framebuffer).map(Some)
+    }
+
+    fn present_framebuffer_and_wait(
+        &self,
+        framebuffer: &Framebuffer,
+    ) -> Result<u64, VirtioDeviceError> {
+        let fence_id = self.alloc_fence_id();
+        self.flush_framebuffer_with_fence(framebuffer, framebuffer.rect, false, Some(fence_id))?;
+        // Frames presented by other threads may complete in any order.
+        let _ =
+            self.last_present_fence
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                    (last == 0 || is_newer(fence_id, last)).then_some(fence_id)
+                });
+        Ok(fence_id)
+    }
+
+    /// Returns the fence id of the last frame presented by
+    /// [`Self::present_and_wait`], or 0 if no frame has been presented.
+    ///
+    /// This does not wait for the device.
+    pub fn last_present_fence(&self) -> u64 {
+        self.last_present_fence.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the fenced command with `fence_id` has completed.
+    ///
+    /// Fences are matched by their exact ids, so this is accurate even if
+    /// other fenced commands are still in flight. Fence ids that have never
+    /// been allocated are reported as completed.
+    pub fn is_fence_signaled(&self, fence_id: u64) -> bool {
+        !self.fences.is_outstanding(fence_id)
+    }
+
+    /// Sets the rotation of the framebuffers set up afterward by
+    /// [`Self::setup_framebuffer`], e.g., for portrait panels.
+    ///
+    /// A rotated framebuffer is drawn upright, with the width and the height
+    /// of the scanout swapped for [`Rotation::Deg90`] and [`Rotation::Deg270`],
+    /// and is rotated clockwise while its pixels are copied to the host. This
+    /// costs a copy on every flush, and rules out blob framebuffers.
+    pub fn set_rotation(&self, rotation: Rotation) {
+        *self.rotation.disable_irq().lock() = rotation;
+    }
+
+    /// Returns the current framebuffer, if any.
+    pub(super) fn current_framebuffer(&self) -> Option<Framebuffer> {
+        self.framebuffer.disable_irq().lock().clone()
+    }
+
+    /// Returns the framebuffer displayed on a scanout, if any.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidScanout`] if the scanout does
+    /// not exist.
+    fn scanout_framebuffer(
+        &self,
+        scanout_id: u32,
+    ) -> Result<Option<Framebuffer>, VirtioDeviceError> {
+        if scanout_id >= self.config_num_scanouts() {
+            return Err(VirtioDeviceError::InvalidScanout(scanout_id));
+        }
+        Ok(self
+            .scanout_framebuffers
+            .disable_irq()
+            .lock()
+            .get(scanout_id as usize)
+            .cloned()
+            .flatten())
+    }
+
+    /// Copies the `rect` area of a framebuffer to the host and flushes it.
+    ///
+    /// `draw_locked` tells whether the caller holds the draw lock of the
+    /// framebuffer.
+    pub(super) fn flush_framebuffer(
+        &self,
+        framebuffer: &Framebuffer,
+        rect: VirtioGpuRect,
+        draw_locked: bool,
+    ) -> Result<(), VirtioDeviceError> {
+        self.flush_framebuffer_with_fence(framebuffer, rect, draw_locked, None)
+    }
+
+    /// Copies the `rect` area of a framebuffer to the host and flushes it,
+    /// with a fenced flush if `fence_id` is given.
+    ///
+    /// Without a fence, the transfer and the flush are submitted in a single
+    /// batch, so they cost one notification and one wait.
+    fn flush_framebuffer_with_fence(
+        &self,
+        framebuffer: &Framebuffer,
+        rect: VirtioGpuRect,
+        draw_locked: bool,
+        fence_id: Option<u64>,
+    ) -> Result<(), VirtioDeviceError> {
+        let Some(fence_id) = fence_id else {
+            return self.batch(|batch| {
+                batch.flush_framebuffer(framebuffer, rect, draw_locked);
+                Ok(())
+            });
+        };
+
+        let flush = self.prepare_flush(framebuffer, rect, draw_locked);
+        let rect = flush.rect();
+        let transferred = if framebuffer.blob {
+            Ok(())
+        } else {
+            self.transfer_to_host_2d(rect, flush.offset(), framebuffer.resource_id)
+        };
+        flush.finish();
+        transferred?;
+        if framebuffer.offscreen {
+            return Ok(());
+        }
+
+        self.check_resource_rect(framebuffer.resource_id, &rect)?;
+        let req = VirtioGpuResourceFlush::new(rect, framebuffer.resource_id).with_fence(fence_id);
+        self.request_nodata(&req)?;
+        self.send_pending_cursor_move()
+    }
+
+    /// Syncs the `rect` area of a framebuffer to the device, with the software
+    /// cursor composited over it.
+    ///
+    /// Compositing the cursor writes to the backing storage, so it must not
+    /// race with drawing. If the caller does not hold the draw lock (see
+    /// `draw_locked`) and another thread does, the cursor is left out and its
+    /// area is flushed again the next time.
+    pub(super) fn prepare_flush<'a>(
+        &self,
+        framebuffer: &'a Framebuffer,
+        rect: VirtioGpuRect,
+        draw_locked: bool,
+    ) -> PreparedFlush<'a> {
+        let draw_guard = if draw_locked {
+            None
+        } else {
+            framebuffer.draw_lock.try_lock()
+        };
+
+        let mut cursor = self.software_cursor.disable_irq().lock();
+        let (rect, covered) = if framebuffer.offscreen || framebuffer.format != CURSOR_FORMAT {
+            // The software cursor is only shown on the displayed framebuffer,
+            // and is only blended with pixels of its own format.
+            (rect, None)
+        } else if draw_locked || draw_guard.is_some() {
+            let rect = match cursor
+                .take_stale()
+                .and_then(|stale| clip_rect(&stale, &framebuffer.rect))
+            {
+                Some(stale) => union_rect(&rect, &stale),
+                None => rect,
+            };
+            let covered = cursor.composite(
+                &framebuffer.backing,
+                framebuffer.stride(),
+                &framebuffer.rect,
+                &rect,
+            );
+            (rect, covered)
+        } else {
+            cursor.skip(&rect);
+            (rect, None)
+        };
+        drop(cursor);
+
+        let rect = match &framebuffer.rotated_backing {
+            Some(rotated_backing) => {
+                framebuffer.rotation.copy_rotated(
+                    &framebuffer.backing,
+                    framebuffer.stride(),
+                    rotated_backing,
+                    framebuffer.host_stride(),
+                    &framebuffer.rect,
+                    &rect,
+                    framebuffer.format.bytes_per_pixel(),
+                );
+                framebuffer.rotation.rotate_rect(&rect, &framebuffer.rect)
+            }
+            None => rect,
+        };
+        // The area is within the framebuffer, whose size has been checked.
+        let (offset, len) =
+            rect_span(&rect, framebuffer.host_stride(), framebuffer.format).unwrap();
+        framebuffer
+            .host_backing()
+            .sync(offset..offset + len)
+            .unwrap();
+
+        PreparedFlush {
+            framebuffer,
+            rect,
+            offset: offset as u64,
+            covered,
+            _draw_guard: draw_guard,
+        }
+    }
+
+    /// Returns the contents of the framebuffer as `R8G8B8A8` pixels, row by row.
+    ///
+    /// The contents are read from the guest backing storage, so they include
+    /// drawing that has not been flushed yet. Returns an empty vector if there
+    /// is no framebuffer.
+    pub fn capture(&self) -> Vec<u8> {
+        let framebuffer = self.framebuffer.disable_irq().lock();
+        match framebuffer.as_ref() {
+            Some(framebuffer) => framebuffer.capture(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Runs `f` with a batch of control commands that notify the device only once.
+    ///
+    /// The commands that `f` queues are submitted when it returns, and this
+    /// waits for all of them. Returns the error of `f`, or else the first error
+    /// of the commands. If the device is being reset, the commands are not sent
+    /// and this fails with [`VirtioDeviceError::NotReady`].
+    ///
+    /// # Deadlocks
+    ///
+    /// The control queue is locked, with IRQs disabled, while `f` runs, so `f`
+    /// must only issue commands through the batch. Calling another control
+    /// command of the device from `f`, e.g., [`Self::flush_rect`], or
+    /// [`Self::set_cursor_image`] without a cursor queue, deadlocks.
+    pub fn batch(
+        &self,
+        f: impl FnOnce(&mut ControlBatch<'_>) -> Result<(), VirtioDeviceError>,
+    ) -> Result<(), VirtioDeviceError> {
+        let queue = self.lock_control_queue();
+        let mut batch =
+            ControlBatch::new(self, queue, &self.control_request, &self.control_response);
+        let result = f(&mut batch);
+        let ended = batch.end_batch();
+        result.and(ended)
+    }
+
+    // ==================== Cursor ====================
+
+    /// Sets the cursor image and shows the cursor at the given position on a scanout.
+    ///
+    /// `image` contains `CURSOR_SIZE * CURSOR_SIZE` pixels in the
+    /// [`VirtioGpuFormat::B8G8R8A8Unorm`] format. `(hot_x, hot_y)` is the
+    /// position of the hot spot in the image. The alpha channel is honored, so
+    /// a fully transparent image shows nothing; see [`arrow_cursor_image`] for
+    /// a ready-made image.
+    ///
+    /// Without a cursor queue, the driver draws the cursor on the framebuffer
+    /// instead (see [`Self::has_hardware_cursor`]), and `scanout_id` is ignored.
+    ///
+    /// Fails with [`VirtioDeviceError::InvalidRect`] if `image` does not have
+    /// the size of a cursor image.
+    ///
+    /// [`arrow_cursor_image`]: super::cursor::arrow_cursor_image
+    pub fn set_cursor_image(
+        &self,
+        scanout_id: u32,
+        image: &[u8],
+        hot_x: u32,
+        hot_y: u32,
+        pos_x: u32,
+        pos_y: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        let size = (CURSOR_SIZE * CURSOR_SIZE) as usize * CURSOR_FORMAT.bytes_per_pixel();
+        if image.len() != size {
+            warn!(
+                "the cursor image has {} bytes, but cursor images have {}",
+                image.len(),
+                size
+            );
+            return Err(VirtioDeviceError::InvalidRect);
+        }
+        if image
+            .chunks_exact(CURSOR_FORMAT.bytes_per_pixel())
+            .all(|pixel| pixel[3] == 0)
+        {
+            warn!("the alpha channel of the cursor image is all zero, so the cursor is invisible");
+        }
+
+        if !self.has_hardware_cursor() {
+            let mut cursor = self.software_cursor.disable_irq().lock();
+            cursor.set_image(Some(image.to_vec()), hot_x, hot_y);
+            cursor.move_to(pos_x, pos_y);
+            drop(cursor);
+            return self.refresh_software_cursor();
+        }
+
+        let resource_id = self.alloc_resource_id();
+        self.resource_create_2d(resource_id, CURSOR_FORMAT, CURSOR_SIZE, CURSOR_SIZE)?;
+
+        // From now on, the resource is released on errors.
+        let backing = match alloc_cursor_backing(image) {
+            Ok(backing) => backing,
+            Err(err) => {
+                self.discard_cursor_resource(resource_id, false);
+                return Err(err);
+            }
+        };
+        if let Err(err) = self.resource_attach_backing(resource_id, &backing) {
+            self.discard_cursor_resource(resource_id, false);
+            return Err(err);
+        }
+
+        let rect = VirtioGpuRect::new(0, 0, CURSOR_SIZE, CURSOR_SIZE);
+        if let Err(err) = self
+            .transfer_to_host_2d(rect, 0, resource_id)
+            .and_then(|()| self.update_cursor(resource_id, scanout_id, pos_x, pos_y, hot_x, hot_y))
+        {
+            self.discard_cursor_resource(resource_id, true);
+            return Err(err);
+        }
+
+        // The device no longer uses the previous cursor image, so release it.
+        let previous = self
+            .cursor_resource
+            .disable_irq()
+            .lock()
+            .replace(resource_id);
+        if let Some(previous) = previous {
+            self.resource_detach_backing(previous)?;
+            self.resource_unref(previous)?;
+        }
+        Ok(())
+    }
+
+    /// Releases a cursor resource that [`Self::set_cursor_image`] has failed
+    /// to show, after detaching its backing storage if it is `attached`.
+    fn discard_cursor_resource(&self, resource_id: u32, attached: bool) {
+        // A hidden cursor must not show the resource again.
+        {
+            let mut update = self.cursor_update.disable_irq().lock();
+            if update.is_some_and(|update| update.resource_id() == resource_id) {
+                *update = None;
+            }
+        }
+
+        if attached {
+            if let Err(err) = self.resource_detach_backing(resource_id) {
+                warn!(
+                    "failed to detach the backing of cursor resource {}: {:?}",
+                    resource_id, err
+                );
+            }
+        }
+        if let Err(err) = self.resource_unref(resource_id) {
+            warn!(
+                "failed to release cursor resource {}: {:?}",
+                resource_id, err
+            );
+        }
+    }
+
+    /// Updates the cursor image to the resource and moves the cursor.
+    ///
+    /// The resource must be a `CURSOR_SIZE * CURSOR_SIZE` one whose contents
+    /// have been transferred to the host, since the device always reads a
+    /// cursor image of that size. Otherwise, this fails with
+    /// [`VirtioDeviceError::InvalidResource`]. A `resource_id` of 0 hides the
+    /// cursor.
+    ///
+    /// Without a cursor queue, the image is read from the guest backing storage
+    /// of the resource, which must be readable, and drawn on the framebuffer.
+    ///
+    /// If the cursor is hidden (see [`Self::hide_cursor`]), the update is only
+    /// shown by [`Self::show_cursor`].
+    pub fn update_cursor(
+        &self,
+        resource_id: u32,
+        scanout_id: u32,
+        pos_x: u32,
+        pos_y: u32,
+        hot_x: u32,
+        hot_y: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        let info = if resource_id != 0 {
+            let info = self
+                .resource_info(resource_id)
+                .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+            if info.width() != CURSOR_SIZE || info.height() != CURSOR_SIZE {
+                warn!(
+                    "cursor resource {} is {}x{}, but cursor images must be {}x{}",
+                    resource_id,
+                    info.width(),
+                    info.height(),
+                    CURSOR_SIZE,
+                    CURSOR_SIZE
+                );
+                return Err(VirtioDeviceError::InvalidResource(resource_id));
+            }
+            Some(info)
+        } else {
+            None
+        };
+
+        if !self.has_hardware_cursor() {
+            let image = match info {
+                Some(info) => Some(read_cursor_image(resource_id, &info)?),
+                None => None,
+            };
+            let mut cursor = self.software_cursor.disable_irq().lock();
+            cursor.set_image(image, hot_x, hot_y);
+            cursor.move_to(pos_x, pos_y);
+            drop(cursor);
+            return self.refresh_software_cursor();
+        }
+
+        // The update moves the cursor as well, so a pending move is outdated.
+        self.pending_cursor_move.disable_irq().lock().take();
+        let pos = VirtioGpuCursorPos::new(scanout_id, pos_x, pos_y);
+        let req = VirtioGpuUpdateCursor::new(pos, resource_id, hot_x, hot_y);
+        *self.cursor_update.disable_irq().lock() = (resource_id != 0).then_some(req);
+        if self.is_cursor_hidden() {
+            return Ok(());
+        }
+        self.request_cursor(&req)
+    }
+
+    /// Moves the cursor without changing its image.
+    ///
+    /// With a cursor queue, this neither takes the control queue nor waits for
+    /// the device, so the cursor keeps moving while control commands, e.g., a
+    /// long flush, are in flight. Without one, the software cursor is flushed
+    /// through the control queue.
+    ///
+    /// If cursor moves are coalesced (see [`Self::set_cursor_coalescing`]),
+    /// this only records the position, which is shown by the next flush.
+    pub fn move_cursor(
+        &self,
+        scanout_id: u32,
+        pos_x: u32,
+        pos_y: u32,
+    ) -> Result<(), VirtioDeviceError> {
+        let coalescing = self.cursor_coalescing.load(Ordering::Relaxed);
+        if !self.has_hardware_cursor() {
+            self.software_cursor
+                .disable_irq()
+                .lock()
+                .move_to(pos_x, pos_y);
+            // The next flush of the framebuffer includes the stale areas.
+            if coalescing {
+                return Ok(());
+            }
+            return self.refresh_software_cursor();
+        }
+
+        let pos = VirtioGpuCursorPos::new(scanout_id, pos_x, pos_y);
+        if let Some(update) = self.cursor_update.disable_irq().lock().as_mut() {
+            update.set_pos(pos);
+        }
+        if self.is_cursor_hidden() {
+            return Ok(());
+        }
+        if coalescing {
+            *self.pending_cursor_move.disable_irq().lock() = Some(pos);
+            return Ok(());
+        }
+        let req = VirtioGpuUpdateCursor::new_move(pos);
+        self.request_cursor(&req)
+    }
+
+    /// Hides the cursor until [`Self::show_cursor`] is called, e.g., for a kiosk.
+    ///
+    /// The hardware cursor is cleared by an update to resource 0, as the
+    /// specification requires. The cursor can still be updated and moved
+    /// while it is hidden, and is shown in its latest state again.
+    pub fn hide_cursor(&self) -> Result<(), VirtioDeviceError> {
+        if self.cursor_hidden.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        if !self.has_hardware_cursor() {
+            self.software_cursor.disable_irq().lock().set_hidden(true);
+            return self.refresh_software_cursor();
+        }
+
+        self.pending_cursor_move.disable_irq().lock().take();
+        let Some(update) = *self.cursor_update.disable_irq().lock() else {
+            // No image has been shown, so there is nothing to clear.
+            return Ok(());
+        };
+        let req = VirtioGpuUpdateCursor::new(update.pos(), 0, 0, 0);
+        self.request_cursor(&req)
+    }
+
+    /// Shows the cursor hidden by [`Self::hide_cursor`] again.
+    pub fn show_cursor(&self) -> Result<(), VirtioDeviceError> {
+        if !self.cursor_hidden.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        if !self.has_hardware_cursor() {
+            self.software_cursor.disable_irq().lock().set_hidden(false);
+            return self.refresh_software_cursor();
+        }
+
+        let Some(update) = *self.cursor_update.disable_irq().lock() else {
+            return Ok(());
+        };
+        self.request_cursor(&update)
+    }
+
+    /// Returns whether the cursor is hidden by [`Self::hide_cursor`].
+    pub fn is_cursor_hidden(&self) -> bool {
+        self.cursor_hidden.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables the coalescing of cursor moves.
+    ///
+    /// A pointer may move the cursor hundreds of times per second. With
+    /// coalescing, [`Self::move_cursor`] does not notify the device, and the
+    /// latest position is sent along with the next flush of the framebuffer
+    /// or by [`Self::flush_cursor`]. Disabling coalescing sends the pending
+    /// position at once.
+    pub fn set_cursor_coalescing(&self, enabled: bool) {
+        self.cursor_coalescing.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            if let Err(err) = self.flush_cursor() {
+                warn!("failed to flush the coalesced cursor moves: {:?}", err);
+            }
+        }
+    }
+
+    /// Shows the latest position of the cursor recorded while cursor moves
+    /// are coalesced.
+    pub fn flush_cursor(&self) -> Result<(), VirtioDeviceError> {
+        if !self.has_hardware_cursor() {
+            return self.refresh_software_cursor();
+        }
+        self.send_pending_cursor_move()
+    }
+
+    /// Sends the pending move of the hardware cursor, if any.
+    pub(super) fn send_pending_cursor_move(&self) -> Result<(), VirtioDeviceError> {
+        let Some(pos) = self.pending_cursor_move.disable_irq().lock().take() else {
+            return Ok(());
+        };
+        let req = VirtioGpuUpdateCursor::new_move(pos);
+        self.request_cursor(&req)
+    }
+
+    /// Flushes the areas of the framebuffer where the software cursor is
+    /// shown in an outdated state.
+    fn refresh_software_cursor(&self) -> Result<(), VirtioDeviceError> {
+        let Some(stale) = self.software_cursor.disable_irq().lock().stale() else {
+            return Ok(());
+        };
+        let Some(framebuffer) = self.current_framebuffer() else {
+            return Ok(());
+        };
+        let Some(rect) = clip_rect(&stale, &framebuffer.rect) else {
+            // The cursor has only been outside of the framebuffer.
+            self.software_cursor.disable_irq().lock().take_stale();
+            return Ok(());
+        };
+        self.flush_framebuffer(&framebuffer, rect, false)
+    }
+
+    // ==================== Queue helpers ====================
+
+    /// Sends a request that expects a `VIRTIO_GPU_RESP_OK_NODATA` response.
+    fn request_nodata<Req: Pod>(&self, req: &Req) -> Result<(), VirtioDeviceError> {
+        let resp: VirtioGpuCtrlHdr = self.request(req)?;
+        check_response(&resp, VirtioGpuCtrlType::RespOkNodata)
+    }
+
+    /// Sends a request on the control queue and waits for its response.
+    fn request<Req: Pod, Resp: Pod>(&self, req: &Req) -> Result<Resp, VirtioDeviceError> {
+        self.request_with_payload::<Req, u8, Resp>(req, &[])
+    }
+
+    /// Locks the control queue.
+    ///
+    /// In ktests, this panics instead of spinning forever if the queue is
+    /// held by a test that checks that the driver does not take it.
+    fn lock_control_queue(&self) -> SpinLockGuard<'_, VirtQueue, LocalIrqDisabled> {
+        #[cfg(ktest)]
+        if self.control_queue_held.load(Ordering::Relaxed) {
+            return self
+                .control_queue
+                .disable_irq()
+                .try_lock()
+                .expect("the control queue is held by the test");
+        }
+        self.control_queue.disable_irq().lock()
+    }
+
+    /// Sends a request followed by `payload` on the control queue and waits for its response.
+    ///
+    /// The request and the payload are placed in separate descriptors.
+    fn request_with_payload<Req: Pod, P: Pod, Resp: Pod>(
+        &self,
+        req: &Req,
+        payload: &[P],
+    ) -> Result<Resp, VirtioDeviceError> {
+        // The request and response buffers are shared by all control commands,
+        // so they must only be accessed with the control queue locked.
+        let mut queue = self.lock_control_queue();
+        self.check_ready()?;
+
+        let mut request_buf = DmaSliceAllocator::new(&self.control_request);
+        let req_slice = request_buf.alloc_val(req);
+        let payload_slice = (!payload.is_empty()).then(|| request_buf.alloc_slice(payload));
+
+        self.request_locked(&mut queue, &req_slice, payload_slice.as_ref())
+    }
+
+    /// Sends a request followed by a payload that the caller has already
+    /// written to and synced in its own DMA buffer.
+    ///
+    /// This is used for payloads that are too large for the shared request buffer.
+    fn request_with_dma_payload<Req: Pod, Resp: Pod>(
+        &self,
+        req: &Req,
+        payload: &DmaStreamSlice<&DmaStream>,
+    ) -> Result<Resp, VirtioDeviceError> {
+        let mut queue = self.lock_control_queue();
+        self.check_ready()?;
+
+        let req_slice = DmaSliceAllocator::new(&self.control_request).alloc_val(req);
+
+        self.request_locked(&mut queue, &req_slice, Some(payload))
+    }
+
+    /// Sends a request on the control queue and waits for its response, of
+    /// which only the valid part is synced from the device.
+    fn request_sized<Req: Pod, Resp: SizedResponse>(
+        &self,
+        req: &Req,
+    ) -> Result<Resp, VirtioDeviceError> {
+        let mut queue = self.lock_control_queue();
+        self.check_ready()?;
+
+        let req_slice = DmaSliceAllocator::new(&self.control_request).alloc_val(req);
+
+        self.request_locked_with(&mut queue, &req_slice, None, |resp_slice| {
+            sync_range(resp_slice, 0..Resp::DATA_OFFSET);
+            let resp: Resp = resp_slice.read_val(0).unwrap();
+            Resp::DATA_OFFSET.saturating_add(resp.data_size())
+        })
+    }
+
+    /// Sends a request on the locked control queue and waits for its response.
+    fn request_locked<Resp: Pod>(
+        &self,
+        queue: &mut VirtQueue,
+        req_slice: &DmaStreamSlice<&DmaStream>,
+        payload: Option<&DmaStreamSlice<&DmaStream>>,
+    ) -> Result<Resp, VirtioDeviceError> {
+        self.request_locked_with(queue, req_slice, payload, |_| size_of::<Resp>())
+    }
+
+    /// Sends a request on the locked control queue and waits for its response.
+    ///
+    /// Only the first `valid_len(resp_slice)` bytes of the response are synced
+    /// from the device, and its header is synced before `valid_len` is called.
+    fn request_locked_with<Resp: Pod>(
+        &self,
+        queue: &mut VirtQueue,
+        req_slice: &DmaStreamSlice<&DmaStream>,
+        payload: Option<&DmaStreamSlice<&DmaStream>>,
+        valid_len: impl FnOnce(&DmaStreamSlice<&DmaStream>) -> usize,
+    ) -> Result<Resp, VirtioDeviceError> {
+        let resp_slice =
+            DmaSliceAllocator::new(&self.control_response).alloc_val(&Resp::new_zeroed());
+
+        let mut inputs = vec![req_slice];
+        if let Some(payload) = payload {
+            inputs.push(payload);
+        }
+
+        // Every request and response starts with a `VirtioGpuCtrlHdr`.
+        let req_hdr: VirtioGpuCtrlHdr = req_slice.read_val(0).unwrap();
+        let cmd_type = req_hdr.type_;
+        trace!("virtio-gpu: command {:#x}", cmd_type);
+        let fence_id = req_hdr.fence();
+
+        if let Some(fence_id) = fence_id {
+            self.fences.begin(fence_id);
+        }
+        let start = read_tsc();
+        // Every command is waited for with the queue locked, so no buffers are
+        // in flight here, and a full queue cannot drain by waiting. The command
+        // fails with `VirtioDeviceError::QueueFull` instead.
+        let token = match queue.add_dma_buf(inputs.as_slice(), &[&resp_slice]) {
+            Ok(token) => token,
+            Err(err) => {
+                if let Some(fence_id) = fence_id {
+                    self.fences.complete(fence_id);
+                }
+                return Err(err.into());
+            }
+        };
+        if queue.should_notify() {
+            queue.notify();
+        }
+        wait_for_token(queue, token);
+        self.record_command(cmd_type, read_tsc().saturating_sub(start));
+        #[cfg(ktest)]
+        self.log_request(req_slice);
+
+        let hdr_len = size_of::<VirtioGpuCtrlHdr>();
+        sync_range(&resp_slice, 0..hdr_len);
+        let resp_hdr: VirtioGpuCtrlHdr = resp_slice.read_val(0).unwrap();
+        trace!(
+            "virtio-gpu: command {:#x} completed with response {:#x}",
+            cmd_type,
+            resp_hdr.type_
+        );
+        if let Some(fence_id) = fence_id {
+            // The device echoes the fence of the command in its response.
+            if resp_hdr.fence() != Some(fence_id) {
+                warn!(
+                    "virtio-gpu: the response to fence {} carries fence {}",
+                    fence_id, resp_hdr.fence_id
+                );
+            }
+            self.fences.complete(fence_id);
+        }
+
+        let len = valid_len(&resp_slice).clamp(hdr_len, resp_slice.nbytes());
+        sync_range(&resp_slice, hdr_len..len);
+        Ok(resp_slice.read_val(0).unwrap())
+    }
+
+    /// Sends a request on the cursor queue.
+    ///
+    /// Cursor commands have no responses, so this does not wait for the device.
+    /// Fails with [`VirtioDeviceError::FeatureNotSupported`] if there is no
+    /// cursor queue.
+    fn request_cursor(&self, req: &VirtioGpuUpdateCursor) -> Result<(), VirtioDeviceError> {
+        let mut cursor_queue = self.cursor_queue.disable_irq().lock();
+        self.check_ready()?;
+        let cursor_queue = cursor_queue
+            .as_mut()
+            .ok_or(VirtioDeviceError::FeatureNotSupported)?;
+        cursor_queue.submit(req)?;
+        self.record_command(req.cmd_type(), 0);
+        #[cfg(ktest)]
+        self.log_command(req.cmd_type(), req.resource_id());
+        Ok(())
+    }
+
+    /// Counts a command sent to the device, which has waited for the device
+    /// for `wait_cycles` TSC cycles.
+    pub(super) fn record_command(&self, cmd_type: u32, wait_cycles: u64) {
+        self.metrics.record(cmd_type, wait_cycles);
+    }
+
+    /// Logs a request sent on the control queue while tests record the
+    /// commands, see [`Self::log_command`].
+    #[cfg(ktest)]
+    pub(super) fn log_request(&self, req_slice: &DmaStreamSlice<&DmaStream>) {
+        let cmd_type: u32 = req_slice.read_val(0).unwrap();
+        let resource_id =
+            resource_id_offset(cmd_type).map_or(0, |offset| req_slice.read_val(offset).unwrap());
+        self.log_command(cmd_type, resource_id);
+    }
+
+    /// Logs a command sent to the device while tests record the commands.
+    ///
+    /// Other commands may be sent at the same time, e.g., by the console
+    /// flushing the boot framebuffer, so tests should pick the commands on
+    /// their own resources out of the log.
+    #[cfg(ktest)]
+    fn log_command(&self, cmd_type: u32, resource_id: u32) {
+        if let Some(log) = self.command_log.disable_irq().lock().as_mut() {
+            log.push((cmd_type, resource_id));
+        }
+    }
+
+    /// Returns the number of commands sent to the device and the time they
+    /// have waited for it, by command type.
+    ///
+    /// This reveals, e.g., redundant commands and the commands that the host
+    /// is slow to complete.
+    pub fn metrics(&self) -> GpuMetrics {
+        self.metrics.snapshot()
+    }
+}
+
+/// Syncs the `range` of `slice` from the device.
+///
+/// Syncing a part of a slice instead of all of it saves cache maintenance for
+/// the bytes that the device has not written.
+fn sync_range(slice: &DmaStreamSlice<&DmaStream>, range: Range<usize>) {
+    if range.is_empty() {
+        return;
+    }
+    let start = slice.offset() + range.start;
+    slice.stream().sync(start..start + range.len()).unwrap();
+}
+
+/// Waits until the device has used the buffer of `token` and reclaims it.
+fn wait_for_token(queue: &mut VirtQueue, token: u16) {
+    wait_for_tokens(queue, &[token]);
+}
+
+/// Waits until the device has used the buffers of all `tokens` and reclaims them.
+///
+/// The used buffers are matched by their descriptor heads instead of assuming
+/// that the device uses the buffers in order. Used buffers of other tokens are
+/// reclaimed and discarded, since no other command is waiting for them.
+///
+/// The commands on the control queue are waited for with the queue locked, so
+/// no buffers are outstanding afterward. In debug builds, this asserts so to
+/// catch the commands, e.g., of a batch, that leak descriptors.
+pub(super) fn wait_for_tokens(queue: &mut VirtQueue, tokens: &[u16]) {
+    let mut num_remaining = tokens.len();
+    while num_remaining > 0 {
+        while !queue.can_pop() {
+            spin_loop();
+        }
+        let (head, _) = queue.pop_used().expect("pop used failed");
+        if tokens.contains(&head) {
+            num_remaining -= 1;
+        } else {
+            warn!("discarding the unexpected used buffer {}", head);
+        }
+    }
+    debug_assert_eq!(
+        queue.outstanding(),
+        0,
+        "buffers of the virtio-gpu control queue are leaked"
+    );
+}
+
+/// Checks that the response has the expected type.
+pub(super) fn check_response(
+    resp: &VirtioGpuCtrlHdr,
+    expected: VirtioGpuCtrlType,
+) -> Result<(), VirtioDeviceError> {
+    if resp.type_ == expected as u32 {
+        Ok(())
+    } else if resp.type_ == VirtioGpuCtrlType::RespErrOutOfMemory as u32 {
+        Err(VirtioDeviceError::HostOutOfMemory)
+    } else {
+        warn!(
+            "virtio-gpu: unexpected response {:#x}, expected {:?}",
+            resp.type_, expected
+        );
+        Err(VirtioDeviceError::ResponseError(resp.type_))
+    }
+}
+
+/// Allocates the backing storage of a framebuffer of the size of `rect`.
+///
+/// If the guest is out of memory for it and `fall_back` is true, a framebuffer
+/// of the smaller [`FALLBACK_RESOLUTION`] is tried before giving up. Returns
+/// the size of the allocated framebuffer along with the backing storage.
+fn alloc_framebuffer_backing(
+    scanout_id: u32,
+    rect: VirtioGpuRect,
+    format: VirtioGpuFormat,
+    fall_back: bool,
+) -> Result<(VirtioGpuRect, DmaStream), VirtioDeviceError> {
+    let mut sizes = vec![(rect.width(), rect.height())];
+    let (fallback_width, fallback_height) = FALLBACK_RESOLUTION;
+    if fall_back && (fallback_width < rect.width() || fallback_height < rect.height()) {
+        sizes.push((
+            fallback_width.min(rect.width()),
+            fallback_height.min(rect.height()),
+        ));
+    }
+
+    let mut result = Err(VirtioDeviceError::GuestOutOfMemory);
+    for (width, height) in sizes {
+        let size = framebuffer_size(width, height, format)?;
+        // The backing is bidirectional so that the host's pixels can be read back
+        // with `transfer_from_host_2d`.
+        result = alloc_dma_stream(size, DmaDirection::Bidirectional).map(|backing| {
+            (
+                VirtioGpuRect::new(rect.x(), rect.y(), width, height),
+                backing,
+            )
+        });
+        if result.is_ok() {
+            break;
+        }
+        warn!(
+            "out of memory for the {}x{} framebuffer of virtio gpu scanout {}",
+            width, height, scanout_id
+        );
+    }
+    result
+}
+
+/// Returns the number of bytes of a framebuffer of the given size and format.
+///
+/// Fails with [`VirtioDeviceError::InvalidRect`] if the framebuffer is empty or
+/// its size does not fit in `usize`.
+pub(super) fn framebuffer_size(
+    width: u32,
+    height: u32,
+    format: VirtioGpuFormat,
+) -> Result<usize, VirtioDeviceError> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(format.bytes_per_pixel()))
+        .filter(|&size| size != 0)
+        .ok_or(VirtioDeviceError::InvalidRect)
+}
+
+/// Allocates the guest backing storage of a cursor resource with the pixels of `image`.
+///
+/// The pixels are copied as they are, so their alpha reaches the host unchanged.
+/// The storage stays readable, so the image can be read back as it is, e.g.,
+/// to draw it as a software cursor with [`GPUDevice::update_cursor`].
+fn alloc_cursor_backing(image: &[u8]) -> Result<DmaStream, VirtioDeviceError> {
+    let backing = alloc_dma_stream(image.len(), DmaDirection::Bidirectional)?;
+    backing.write_bytes(0, image).unwrap();
+    backing.sync(0..image.len()).unwrap();
+    Ok(backing)
+}
+
+/// Reads the image of a cursor resource from its guest backing storage.
+fn read_cursor_image(resource_id: u32, info: &ResourceInfo) -> Result<Vec<u8>, VirtioDeviceError> {
+    let backing = info
+        .backing()
+        .ok_or(VirtioDeviceError::InvalidResource(resource_id))?;
+    let size = (CURSOR_SIZE * CURSOR_SIZE) as usize * CURSOR_FORMAT.bytes_per_pixel();
+    let mut image = vec![0u8; size];
+    backing.sync(0..size).unwrap();
+    backing
+        .read_bytes(0, &mut image)
+        .map_err(|_| VirtioDeviceError::InvalidResource(resource_id))?;
+    Ok(image)
+}
+
+/// Returns the non-empty areas of `bounds` above, below, left and right of
+/// `rect`, which must lie within `bounds`.
+fn border_rects(rect: &VirtioGpuRect, bounds: &VirtioGpuRect) -> Vec<VirtioGpuRect> {
+    let (x, y, width, height) = (rect.x(), rect.y(), rect.width(), rect.height());
+    let (bounds_width, bounds_height) = (bounds.width(), bounds.height());
+    [
+        VirtioGpuRect::new(0, 0, bounds_width, y),
+        VirtioGpuRect::new(0, y + height, bounds_width, bounds_height - (y + height)),
+        VirtioGpuRect::new(0, y, x, height),
+        VirtioGpuRect::new(x + width, y, bounds_width - (x + width), height),
+    ]
+    .into_iter()
+    .filter(|band| band.width() != 0 && band.height() != 0)
+    .collect()
+}
+
+/// Returns the offset of the first byte of `rect` in a buffer with the given
+/// stride and format, and the number of bytes from that offset to the end of `rect`.
+///
+/// Returns `None` if either overflows.
+pub(super) fn rect_span(
+    rect: &VirtioGpuRect,
+    stride: usize,
+    format: VirtioGpuFormat,
+) -> Option<(usize, usize)> {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let offset = pixel_offset(rect.x(), rect.y(), stride, bytes_per_pixel)?;
+    let len = pixel_offset(
+        rect.width(),
+        rect.height().saturating_sub(1),
+        stride,
+        bytes_per_pixel,
+    )?;
+    offset.checked_add(len)?;
+    Some((offset, len))
+}
+
+/// Traces the number of bytes that a transfer of the `rect` area of a
+/// resource moves to the host.
+///
+/// This shows, e.g., how much a full-screen flush costs compared to flushing
+/// only the damaged area.
+pub(super) fn trace_transfer(resource_id: u32, rect: &VirtioGpuRect, format: VirtioGpuFormat) {
+    let bytes = rect.width() as u64 * rect.height() as u64 * format.bytes_per_pixel() as u64;
+    trace!(
+        "virtio-gpu: transferring {} bytes of {} of resource {} to the host",
+        bytes,
+        rect,
+        resource_id
+    );
+}
+
+/// Returns the scanout given by the `virtio_gpu.scanout=INDEX` kernel command-line argument.
+fn scanout_from_cmdline() -> Option<u32> {
+    let value = boot_info()
+        .kernel_cmdline
+        .split(' ')
+        .find_map(|arg| arg.strip_prefix("virtio_gpu.scanout="))?;
+
+    let scanout_id = value.parse().ok();
+    if scanout_id.is_none() {
+        warn!("invalid virtio_gpu.scanout argument: {}", value);
+    }
+    scanout_id
+}
+
+/// Returns the maximum resolution of the framebuffer given by the
+/// `virtio_gpu.max_resolution=WIDTHxHEIGHT` kernel command-line argument, or
+/// [`DEFAULT_MAX_RESOLUTION`] if there is no valid one.
+fn max_resolution_from_cmdline() -> (u32, u32) {
+    let Some(value) = boot_info()
+        .kernel_cmdline
+        .split(' ')
+        .find_map(|arg| arg.strip_prefix("virtio_gpu.max_resolution="))
+    else {
+        return DEFAULT_MAX_RESOLUTION;
+    };
+
+    let resolution = value
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .filter(|&(width, height)| width != 0 && height != 0);
+    resolution.unwrap_or_else(|| {
+        warn!("invalid virtio_gpu.max_resolution argument: {}", value);
+        DEFAULT_MAX_RESOLUTION
+    })
+}
+
+/// Sets up the framebuffer at boot.
+///
+/// The framebuffer is left blank for its future owner, unless the `gpu_demo`
+/// feature is enabled.
+fn init_frame_buffer(
+    device: &Arc<GPUDevice>,
+    scanout_id: Option<u32>,
+) -> Result<FramebufferResource, VirtioDeviceError> {
+    let framebuffer = device.setup_framebuffer(scanout_id)?;
+
+    #[cfg(feature = "gpu_demo")]
+    super::demo::draw_demo(device, &framebuffer)?;
+    #[cfg(not(feature = "gpu_demo"))]
+    framebuffer.lock().flush()?;
+
+    Ok(framebuffer)
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::gpu::test_device;
+
+    #[ktest]
+    fn framebuffer_setup_commands_are_ordered() {
+        let Some(device) = test_device() else {
+            return;
+        };
+        // The framebuffer of the test replaces the current one, which is put
+        // back on the display afterward. The ktests run one at a time, so no
+        // other test sees the replacement.
+        let previous_scanouts = *device.scanouts.disable_irq().lock();
+        let previous_framebuffer = device.current_framebuffer();
+        let previous_scanout_framebuffers =
+            device.scanout_framebuffers.disable_irq().lock().clone();
+
+        // The display info is cached, so drop it to have it requested (and
+        // cached) again.
+        *device.display_info.disable_irq().lock() = None;
+        *device.command_log.disable_irq().lock() = Some(Vec::new());
+        let framebuffer = device.setup_framebuffer(None);
+        let log = device.command_log.disable_irq().lock().take().unwrap();
+        let framebuffer = framebuffer.unwrap();
+        let resource_id = framebuffer.resource_id();
+        assert_ne!(resource_id, 0);
+
+        let expected = if framebuffer.framebuffer.blob {
+            [
+                VirtioGpuCtrlType::CmdResourceCreateBlob,
+                VirtioGpuCtrlType::CmdSetScanoutBlob,
+            ]
+            .as_slice()
+        } else {
+            [
+                VirtioGpuCtrlType::CmdResourceCreate2d,
+                VirtioGpuCtrlType::CmdResourceAttachBacking,
+                VirtioGpuCtrlType::CmdSetScanout,
+            ]
+            .as_slice()
+        };
+        let expected: Vec<(u32, u32)> = expected
+            .iter()
+            .map(|&cmd_type| (cmd_type as u32, resource_id))
+            .collect();
+        // Other commands, e.g., for the EDID or from the console, may come in
+        // between, but none of them is on the new resource.
+        let sent: Vec<(u32, u32)> = log
+            .iter()
+            .copied()
+            .filter(|&(_, id)| id == resource_id)
+            .collect();
+        assert_eq!(sent, expected);
+
+        // The resource is created with the size of the display info, so the
+        // display info is requested before.
+        let created = log.iter().position(|&entry| entry == expected[0]);
+        let requested = log
+            .iter()
+            .position(|&(cmd_type, _)| cmd_type == VirtioGpuCtrlType::CmdGetDisplayInfo as u32);
+        assert!(
+            matches!((requested, created), (Some(requested), Some(created)) if requested < created)
+        );
+
+        let rect = framebuffer.rect();
+        let size = framebuffer_size(rect.width(), rect.height(), FRAMEBUFFER_FORMAT).unwrap();
+        assert!(framebuffer.backing().nbytes() >= size);
+
+        let scanout_id = framebuffer.scanout_id().unwrap();
+        drop(framebuffer);
+        if let Some((resource_id, rect)) = previous_scanouts[scanout_id as usize] {
+            device.set_scanout(scanout_id, resource_id, rect).unwrap();
+        }
+        *device.framebuffer.disable_irq().lock() = previous_framebuffer;
+        *device.scanout_framebuffers.disable_irq().lock() = previous_scanout_framebuffers;
+    }
+
+    #[ktest]
+    fn seeded_resource_ids_are_allocated_in_order() {
+        let Some(device) = test_device() else {
+            return;
+        };
+        let previous = device.next_resource_id.load(Ordering::Relaxed);
+
+        assert!(matches!(
+            device.seed_resource_ids(0),
+            Err(VirtioDeviceError::InvalidResource(0))
+        ));
+        assert_eq!(device.next_resource_id.load(Ordering::Relaxed), previous);
+
+        device.seed_resource_ids(0x4000_0000).unwrap();
+        assert_eq!(device.alloc_resource_id(), 0x4000_0000);
+        assert_eq!(device.alloc_resource_id(), 0x4000_0001);
+        device.seed_resource_ids(0x4000_0000).unwrap();
+        assert_eq!(device.alloc_resource_id(), 0x4000_0000);
+
+        // No resource has been created with the ids above, so they may be
+        // allocated again.
+        device.seed_resource_ids(previous).unwrap();
+    }
+
+    #[ktest]
+    fn cursor_moves_while_a_control_command_is_in_flight() {
+        let Some(device) = test_device().filter(|device| device.has_hardware_cursor()) else {
+            return;
+        };
+        // Neither coalescing nor a hidden cursor may turn the move into a no-op.
+        let was_coalescing = device.cursor_coalescing.load(Ordering::Relaxed);
+        let was_hidden = device.is_cursor_hidden();
+        device.set_cursor_coalescing(false);
+        device.show_cursor().unwrap();
+        let previous_pos = device
+            .cursor_update
+            .disable_irq()
+            .lock()
+            .map(|update| update.pos());
+        let scanout_id = device
+            .scanouts
+            .disable_irq()
+            .lock()
+            .iter()
+            .position(Option::is_some)
+            .unwrap_or(0) as u32;
+
+        // Send a control command without waiting for it, as a slow command
+        // does, with the control queue locked. Taking the queue from now on
+        // panics instead of spinning forever.
+        let mut control_queue = device.control_queue.disable_irq().lock();
+        let req = VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdGetDisplayInfo);
+        let req_slice = DmaSliceAllocator::new(&device.control_request).alloc_val(&req);
+        let resp_slice = DmaSliceAllocator::new(&device.control_response)
+            .alloc_val(&VirtioGpuRespDisplayInfo::new_zeroed());
+        let token = control_queue
+            .add_dma_buf(&[&req_slice], &[&resp_slice])
+            .unwrap();
+        control_queue.notify();
+        device.control_queue_held.store(true, Ordering::Relaxed);
+
+        *device.command_log.disable_irq().lock() = Some(Vec::new());
+        let moved = device.move_cursor(scanout_id, 10, 10);
+        let log = device.command_log.disable_irq().lock().take().unwrap();
+        device.control_queue_held.store(false, Ordering::Relaxed);
+        moved.unwrap();
+        assert_eq!(log, [(VirtioGpuCtrlType::CmdMoveCursor as u32, 0)]);
+
+        let deadline = read_tsc().saturating_add(duration_to_cycles(Duration::from_secs(1)));
+        loop {
+            let mut cursor_queue = device.cursor_queue.disable_irq().lock();
+            if cursor_queue.as_mut().unwrap().is_idle() {
+                break;
+            }
+            assert!(
+                read_tsc() < deadline,
+                "the cursor move waits for the control queue"
+            );
+            drop(cursor_queue);
+            spin_loop();
+        }
+
+        wait_for_token(&mut control_queue, token);
+        drop(control_queue);
+
+        // Put the cursor back where it was shown.
+        if let Some(pos) = previous_pos {
+            if let Some(update) = device.cursor_update.disable_irq().lock().as_mut() {
+                update.set_pos(pos);
+            }
+            device
+                .request_cursor(&VirtioGpuUpdateCursor::new_move(pos))
+                .unwrap();
+        }
+        if was_hidden {
+            device.hide_cursor().unwrap();
+        }
+        device.set_cursor_coalescing(was_coalescing);
+    }
+
+    #[ktest]
+    fn semi_transparent_cursor_round_trips() {
+        let size = (CURSOR_SIZE * CURSOR_SIZE) as usize * CURSOR_FORMAT.bytes_per_pixel();
+        let image: Vec<u8> = (0..size)
+            .map(|index| match index % 4 {
+                3 => (index / 4) as u8,
+                channel => 0x40 * channel as u8,
+            })
+            .collect();
+
+        let stride = CURSOR_SIZE as usize * CURSOR_FORMAT.bytes_per_pixel();
+        let mut info = ResourceInfo::new(CURSOR_SIZE, CURSOR_SIZE, CURSOR_FORMAT, stride);
+        info.set_backing(Some(alloc_cursor_backing(&image).unwrap()));
+        assert_eq!(read_cursor_image(1, &info).unwrap(), image);
+    }
+
+    #[ktest]
+    fn padded_stride_extends_backing_size() {
+        let format = VirtioGpuFormat::B8G8R8A8Unorm;
+        let packed = ResourceInfo::new(100, 10, format, 400);
+        assert!(packed.is_packed());
+        assert_eq!(packed.backing_size(), 4000);
+
+        let padded = ResourceInfo::new(100, 10, format, 512);
+        assert!(!padded.is_packed());
+        assert_eq!(padded.backing_size(), 9 * 512 + 400);
+    }
+
+    #[ktest]
+    fn framebuffer_size_checks_overflow() {
+        let format = VirtioGpuFormat::B8G8R8A8Unorm;
+        assert!(matches!(
+            framebuffer_size(1024, 768, format),
+            Ok(size) if size == 1024 * 768 * 4
+        ));
+        assert!(matches!(
+            framebuffer_size(0, 768, format),
+            Err(VirtioDeviceError::InvalidRect)
+        ));
+        assert!(matches!(
+            framebuffer_size(u32::MAX, u32::MAX, format),
+            Err(VirtioDeviceError::InvalidRect)
+        ));
+    }
+
+    #[ktest]
+    fn sub_rect_transfers_start_at_its_first_pixel() {
+        let format = VirtioGpuFormat::B8G8R8A8Unorm;
+        let stride = 1024 * 4;
+        assert_eq!(
+            rect_span(&VirtioGpuRect::new(0, 0, 1024, 768), stride, format),
+            Some((0, 768 * stride))
+        );
+
+        // The offset is that of the top-left pixel, and the span ends with the
+        // last pixel of the bottom row rather than at the end of the row.
+        let (offset, len) =
+            rect_span(&VirtioGpuRect::new(100, 50, 20, 10), stride, format).unwrap();
+        assert_eq!(offset, 50 * stride + 100 * 4);
+        assert_eq!(len, 9 * stride + 20 * 4);
+
+        let huge = VirtioGpuRect::new(u32::MAX, u32::MAX, 1, 1);
+        assert_eq!(rect_span(&huge, usize::MAX / 2, format), None);
+    }
+
+    #[ktest]
+    fn border_surrounds_the_scanout_area() {
+        let display = VirtioGpuRect::new(0, 0, 1024, 768);
+        assert_eq!(
+            border_rects(&VirtioGpuRect::new(112, 84, 800, 600), &display),
+            [
+                VirtioGpuRect::new(0, 0, 1024, 84),
+                VirtioGpuRect::new(0, 684, 1024, 84),
+                VirtioGpuRect::new(0, 84, 112, 600),
+                VirtioGpuRect::new(912, 84, 112, 600),
+            ]
+        );
+
+        // An area in a corner leaves only two bands, and a full one none.
+        assert_eq!(
+            border_rects(&VirtioGpuRect::new(0, 0, 800, 600), &display),
+            [
+                VirtioGpuRect::new(0, 600, 1024, 168),
+                VirtioGpuRect::new(800, 0, 224, 600),
+            ]
+        );
+        assert!(border_rects(&display, &display).is_empty());
+    }
+}
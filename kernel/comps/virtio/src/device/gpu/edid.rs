@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Parsing of the EDID (Extended Display Identification Data) of scanouts.
+
+use alloc::vec::Vec;
+
+/// The size of an EDID block, in bytes.
+pub const EDID_BLOCK_SIZE: usize = 128;
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// The offset of the first detailed timing descriptor, which describes the preferred timing.
+const PREFERRED_TIMING_OFFSET: usize = 54;
+
+/// The offset of the number of extension blocks in the base block.
+const EXTENSION_COUNT_OFFSET: usize = 126;
+
+/// The reasons why an EDID is malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdidError {
+    /// The EDID is shorter than its base block and the extension blocks that
+    /// the base block announces, the element is its length.
+    Truncated(usize),
+    /// The block does not start with the fixed EDID header.
+    BadHeader,
+    /// The checksum byte at the end of the block is `stored`, but `expected`
+    /// makes the bytes of the block sum to zero.
+    BadChecksum { stored: u8, expected: u8 },
+    /// Like [`Self::BadChecksum`], for the extension block at `index`,
+    /// counted from 0 after the base block.
+    BadExtensionChecksum {
+        index: usize,
+        stored: u8,
+        expected: u8,
+    },
+}
+
+/// The type of an EDID extension block, given by its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdidExtension {
+    /// A CEA-861 block, with the audio and video capabilities of HDMI displays.
+    Cea861,
+    /// A video timing block, with more timings than the base block has room for.
+    VideoTiming,
+    /// A DisplayID block.
+    DisplayId,
+    /// A block map, which lists the types of the other extension blocks.
+    BlockMap,
+    /// Another type, the element is the tag.
+    Other(u8),
+}
+
+impl From<u8> for EdidExtension {
+    fn from(tag: u8) -> Self {
+        match tag {
+            0x02 => Self::Cea861,
+            0x10 => Self::VideoTiming,
+            0x70 => Self::DisplayId,
+            0xf0 => Self::BlockMap,
+            tag => Self::Other(tag),
+        }
+    }
+}
+
+/// The base block of an EDID, with the types of its extension blocks.
+#[derive(Debug, Clone)]
+pub struct Edid {
+    block: [u8; EDID_BLOCK_SIZE],
+    extensions: Vec<EdidExtension>,
+}
+
+impl Edid {
+    /// Parses the base block at the beginning of `bytes`, and the extension
+    /// blocks that follow it.
+    ///
+    /// Fails if a block is truncated or has a wrong checksum, or if the base
+    /// block has a wrong header.
+    pub fn parse(bytes: &[u8]) -> Result<Self, EdidError> {
+        let block: [u8; EDID_BLOCK_SIZE] = bytes
+            .get(..EDID_BLOCK_SIZE)
+            .and_then(|block| block.try_into().ok())
+            .ok_or(EdidError::Truncated(bytes.len()))?;
+        if block[..EDID_HEADER.len()] != EDID_HEADER {
+            return Err(EdidError::BadHeader);
+        }
+        let stored = block[EDID_BLOCK_SIZE - 1];
+        let expected = expected_checksum(&block);
+        if stored != expected {
+            return Err(EdidError::BadChecksum { stored, expected });
+        }
+
+        let num_extensions = block[EXTENSION_COUNT_OFFSET] as usize;
+        let len = (num_extensions + 1) * EDID_BLOCK_SIZE;
+        if bytes.len() < len {
+            return Err(EdidError::Truncated(bytes.len()));
+        }
+        let mut extensions = Vec::with_capacity(num_extensions);
+        for (index, extension) in bytes[EDID_BLOCK_SIZE..len]
+            .chunks_exact(EDID_BLOCK_SIZE)
+            .enumerate()
+        {
+            let extension: &[u8; EDID_BLOCK_SIZE] = extension.try_into().unwrap();
+            let stored = extension[EDID_BLOCK_SIZE - 1];
+            let expected = expected_checksum(extension);
+            if stored != expected {
+                return Err(EdidError::BadExtensionChecksum {
+                    index,
+                    stored,
+                    expected,
+                });
+            }
+            extensions.push(EdidExtension::from(extension[0]));
+        }
+
+        Ok(Self { block, extensions })
+    }
+
+    /// Returns the width and height of the preferred timing, in pixels.
+    pub fn preferred_resolution(&self) -> Option<(u32, u32)> {
+        let dtd = &self.block[PREFERRED_TIMING_OFFSET..PREFERRED_TIMING_OFFSET + 18];
+        // A zero pixel clock means that the descriptor is not a timing descriptor.
+        if dtd[0] == 0 && dtd[1] == 0 {
+            return None;
+        }
+
+        let width = dtd[2] as u32 | ((dtd[4] as u32 & 0xf0) << 4);
+        let height = dtd[5] as u32 | ((dtd[7] as u32 & 0xf0) << 4);
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some((width, height))
+    }
+
+    /// Returns the number of extension blocks that follow the base block.
+    pub fn num_extensions(&self) -> usize {
+        self.block[EXTENSION_COUNT_OFFSET] as usize
+    }
+
+    /// Returns the types of the extension blocks, in order.
+    pub fn extensions(&self) -> &[EdidExtension] {
+        &self.extensions
+    }
+}
+
+/// Returns the checksum byte that makes all bytes of the block sum to zero (mod 256).
+fn expected_checksum(block: &[u8; EDID_BLOCK_SIZE]) -> u8 {
+    let sum = block[..EDID_BLOCK_SIZE - 1]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    sum.wrapping_neg()
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn malformed_edids_are_rejected() {
+        let mut block = [0u8; EDID_BLOCK_SIZE];
+        block[..EDID_HEADER.len()].copy_from_slice(&EDID_HEADER);
+        block[EDID_BLOCK_SIZE - 1] = expected_checksum(&block);
+        assert!(Edid::parse(&block).is_ok());
+
+        assert_eq!(
+            Edid::parse(&block[..64]).unwrap_err(),
+            EdidError::Truncated(64)
+        );
+
+        let stored = block[EDID_BLOCK_SIZE - 1];
+        block[EDID_BLOCK_SIZE - 1] = stored.wrapping_add(1);
+        assert_eq!(
+            Edid::parse(&block).unwrap_err(),
+            EdidError::BadChecksum {
+                stored: stored.wrapping_add(1),
+                expected: stored
+            }
+        );
+
+        block[0] = 0xff;
+        assert_eq!(Edid::parse(&block).unwrap_err(), EdidError::BadHeader);
+    }
+
+    #[ktest]
+    fn extension_blocks_are_checked() {
+        let mut bytes = [0u8; 3 * EDID_BLOCK_SIZE];
+        let (base, extensions) = bytes.split_at_mut(EDID_BLOCK_SIZE);
+        let base: &mut [u8; EDID_BLOCK_SIZE] = base.try_into().unwrap();
+        base[..EDID_HEADER.len()].copy_from_slice(&EDID_HEADER);
+        base[EXTENSION_COUNT_OFFSET] = 2;
+        base[EDID_BLOCK_SIZE - 1] = expected_checksum(base);
+        for (extension, tag) in extensions
+            .chunks_exact_mut(EDID_BLOCK_SIZE)
+            .zip([0x02, 0x42])
+        {
+            let extension: &mut [u8; EDID_BLOCK_SIZE] = extension.try_into().unwrap();
+            extension[0] = tag;
+            extension[EDID_BLOCK_SIZE - 1] = expected_checksum(extension);
+        }
+
+        let edid = Edid::parse(&bytes).unwrap();
+        assert_eq!(
+            edid.extensions(),
+            [EdidExtension::Cea861, EdidExtension::Other(0x42)]
+        );
+
+        assert_eq!(
+            Edid::parse(&bytes[..2 * EDID_BLOCK_SIZE]).unwrap_err(),
+            EdidError::Truncated(2 * EDID_BLOCK_SIZE)
+        );
+
+        let last = bytes.len() - 1;
+        let stored = bytes[last];
+        bytes[last] = stored.wrapping_add(1);
+        assert_eq!(
+            Edid::parse(&bytes).unwrap_err(),
+            EdidError::BadExtensionChecksum {
+                index: 1,
+                stored: stored.wrapping_add(1),
+                expected: stored
+            }
+        );
+    }
+}
@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! The fences of the commands sent to the device.
+//!
+//! Fenced commands may be in flight at the same time and complete in any
+//! order, so a fence is matched by its exact id rather than by comparing it
+//! with the last completed one. Fence ids wrap around, so they are only
+//! ordered relative to each other by [`is_newer`].
+
+use alloc::collections::BTreeSet;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use ostd::sync::SpinLock;
+
+/// The fence ids allocated to the commands and the ones still in flight.
+#[derive(Debug)]
+pub(super) struct FenceTracker {
+    next_id: AtomicU64,
+    outstanding: SpinLock<BTreeSet<u64>>,
+}
+
+impl FenceTracker {
+    pub(super) const fn new() -> Self {
+        Self::starting_at(1)
+    }
+
+    const fn starting_at(next_id: u64) -> Self {
+        Self {
+            next_id: AtomicU64::new(next_id),
+            outstanding: SpinLock::new(BTreeSet::new()),
+        }
+    }
+
+    /// Allocates an unused fence id.
+    ///
+    /// After the ids wrap around, 0 and the ids of the fences still in flight
+    /// are skipped, so no two fences in flight share an id.
+    pub(super) fn alloc(&self) -> u64 {
+        let outstanding = self.outstanding.disable_irq().lock();
+        loop {
+            let fence_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            if fence_id != 0 && !outstanding.contains(&fence_id) {
+                return fence_id;
+            }
+        }
+    }
+
+    /// Records that a command with `fence_id` has been submitted.
+    pub(super) fn begin(&self, fence_id: u64) {
+        self.outstanding.disable_irq().lock().insert(fence_id);
+    }
+
+    /// Records that the command with `fence_id` has completed.
+    ///
+    /// Returns `false` if no command with `fence_id` is in flight.
+    pub(super) fn complete(&self, fence_id: u64) -> bool {
+        self.outstanding.disable_irq().lock().remove(&fence_id)
+    }
+
+    /// Returns whether a command with `fence_id` is in flight.
+    pub(super) fn is_outstanding(&self, fence_id: u64) -> bool {
+        self.outstanding.disable_irq().lock().contains(&fence_id)
+    }
+}
+
+/// Returns whether fence id `a` has been allocated after `b`.
+///
+/// The ids wrap around, so this holds as long as fewer than `2^63` fences are
+/// allocated in between.
+pub(super) fn is_newer(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) > 0
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn alloc_skips_outstanding_after_wraparound() {
+        let fences = FenceTracker::starting_at(u64::MAX);
+        let last = fences.alloc();
+        assert_eq!(last, u64::MAX);
+        fences.begin(last);
+        fences.begin(1);
+
+        assert_eq!(fences.alloc(), 2);
+        assert!(fences.complete(1));
+        assert!(!fences.complete(1));
+        assert!(fences.is_outstanding(last));
+    }
+
+    #[ktest]
+    fn newer_across_wraparound() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 2));
+        assert!(!is_newer(1, 1));
+        assert!(is_newer(1, u64::MAX));
+        assert!(!is_newer(u64::MAX, 1));
+    }
+}
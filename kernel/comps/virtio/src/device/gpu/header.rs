@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use bitflags::bitflags;
+use int_to_c_enum::TryFromInt;
+use ostd::Pod;
+
+/// The type of a request or response on the control queue and the cursor queue.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromInt)]
+pub enum VirtioGpuCtrlType {
+    /* 2D commands */
+    CmdGetDisplayInfo = 0x0100,
+    CmdResourceCreate2d = 0x0101,
+    CmdResourceUnref = 0x0102,
+    CmdSetScanout = 0x0103,
+    CmdResourceFlush = 0x0104,
+    CmdTransferToHost2d = 0x0105,
+    CmdResourceAttachBacking = 0x0106,
+    CmdResourceDetachBacking = 0x0107,
+    CmdGetCapsetInfo = 0x0108,
+    CmdGetEdid = 0x010a,
+    CmdResourceAssignUuid = 0x010b,
+    CmdResourceCreateBlob = 0x010c,
+    CmdSetScanoutBlob = 0x010d,
+
+    /* 3D commands */
+    CmdCtxCreate = 0x0200,
+    CmdCtxDestroy = 0x0201,
+    CmdCtxAttachResource = 0x0202,
+    CmdCtxDetachResource = 0x0203,
+    CmdResourceCreate3d = 0x0204,
+    CmdTransferToHost3d = 0x0205,
+    CmdTransferFromHost3d = 0x0206,
+    CmdSubmit3d = 0x0207,
+    CmdResourceMapBlob = 0x0208,
+    CmdResourceUnmapBlob = 0x0209,
+
+    /* Cursor commands */
+    CmdUpdateCursor = 0x0300,
+    CmdMoveCursor = 0x0301,
+
+    /* Success responses */
+    RespOkNodata = 0x1100,
+    RespOkDisplayInfo = 0x1101,
+    RespOkCapsetInfo = 0x1102,
+    RespOkEdid = 0x1104,
+    RespOkResourceUuid = 0x1105,
+    RespOkMapInfo = 0x1106,
+
+    /* Error responses */
+    RespErrUnspec = 0x1200,
+    RespErrOutOfMemory = 0x1201,
+    RespErrInvalidScanoutId = 0x1202,
+    RespErrInvalidResourceId = 0x1203,
+    RespErrInvalidContextId = 0x1204,
+    RespErrInvalidParameter = 0x1205,
+}
+
+bitflags! {
+    /// The flags of [`VirtioGpuCtrlHdr`].
+    pub struct CtrlFlags: u32 {
+        /// The device completes the command only after the host has finished
+        /// executing it, and echoes the fence id in the response.
+        const FENCE = 1 << 0;
+        /// The ring index of the header is valid, which requires
+        /// `VIRTIO_GPU_F_CONTEXT_INIT`.
+        const INFO_RING_IDX = 1 << 1;
+    }
+}
+
+/// The header shared by all requests and responses.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Pod)]
+pub struct VirtioGpuCtrlHdr {
+    /// The request or response type, see [`VirtioGpuCtrlType`].
+    pub type_: u32,
+    /// The request or response flags, see [`CtrlFlags`].
+    pub flags: u32,
+    /// The fence id, valid only if the fence flag is set.
+    pub fence_id: u64,
+    /// The rendering context, used only by 3D commands.
+    pub ctx_id: u32,
+    /// The index of the context-specific ring.
+    pub ring_idx: u8,
+    pub padding: [u8; 3],
+}
+
+impl VirtioGpuCtrlHdr {
+    /// Creates a header of the given type with all other fields zeroed.
+    pub const fn from_type(type_: VirtioGpuCtrlType) -> Self {
+        Self {
+            type_: type_ as u32,
+            flags: 0,
+            fence_id: 0,
+            ctx_id: 0,
+            ring_idx: 0,
+            padding: [0; 3],
+        }
+    }
+
+    /// Returns the header with the rendering context of a 3D command.
+    pub fn with_ctx_id(mut self, ctx_id: u32) -> Self {
+        self.ctx_id = ctx_id;
+        self
+    }
+
+    /// Returns the header of a command fenced with the given fence id.
+    pub fn with_fence(mut self, fence_id: u64) -> Self {
+        self.set_fence(fence_id);
+        self
+    }
+
+    /// Marks the command as fenced with the given fence id.
+    pub fn set_fence(&mut self, fence_id: u64) {
+        self.flags |= CtrlFlags::FENCE.bits();
+        self.fence_id = fence_id;
+    }
+
+    /// Returns the known flags of the header.
+    pub fn ctrl_flags(&self) -> CtrlFlags {
+        CtrlFlags::from_bits_truncate(self.flags)
+    }
+
+    /// Returns the fence id, or `None` if the header is not fenced.
+    pub fn fence(&self) -> Option<u64> {
+        self.ctrl_flags()
+            .contains(CtrlFlags::FENCE)
+            .then_some(self.fence_id)
+    }
+
+    /// Returns the type of the header, if it is a known one.
+    pub fn ctrl_type(&self) -> Option<VirtioGpuCtrlType> {
+        VirtioGpuCtrlType::try_from(self.type_).ok()
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn fenced_headers() {
+        let hdr = VirtioGpuCtrlHdr::from_type(VirtioGpuCtrlType::CmdSubmit3d).with_ctx_id(2);
+        assert_eq!(hdr.fence(), None);
+        assert_eq!(hdr.ctx_id, 2);
+
+        let hdr = hdr.with_fence(7);
+        assert_eq!(hdr.ctrl_flags(), CtrlFlags::FENCE);
+        assert_eq!(hdr.fence(), Some(7));
+        assert_eq!(hdr.ctx_id, 2);
+    }
+}
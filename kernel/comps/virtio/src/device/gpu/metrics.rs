@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! The metrics of the commands sent to the device.
+
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use ostd::arch::tsc_freq;
+
+use super::header::VirtioGpuCtrlType;
+
+/// The number of command types in each class of commands.
+///
+/// The 2D, 3D and cursor commands are numbered from `0x0100`, `0x0200` and
+/// `0x0300`, respectively.
+const COMMANDS_PER_CLASS: usize = 0x10;
+const NUM_CLASSES: usize = 3;
+const NUM_COUNTERS: usize = COMMANDS_PER_CLASS * NUM_CLASSES;
+
+/// The counters of the commands sent to the device.
+///
+/// The counters are atomic, so commands are counted without taking a lock.
+#[derive(Debug)]
+pub(super) struct CommandCounters {
+    counters: [CommandCounter; NUM_COUNTERS],
+}
+
+#[derive(Debug)]
+struct CommandCounter {
+    count: AtomicU64,
+    /// The total time waited for the device, in TSC cycles.
+    wait_cycles: AtomicU64,
+}
+
+impl CommandCounters {
+    pub(super) const fn new() -> Self {
+        Self {
+            counters: [const {
+                CommandCounter {
+                    count: AtomicU64::new(0),
+                    wait_cycles: AtomicU64::new(0),
+                }
+            }; NUM_COUNTERS],
+        }
+    }
+
+    /// Counts a command of `cmd_type`, which has waited for the device for
+    /// `wait_cycles` TSC cycles.
+    ///
+    /// Commands of unknown types are not counted.
+    pub(super) fn record(&self, cmd_type: u32, wait_cycles: u64) {
+        let Some(index) = counter_index(cmd_type) else {
+            return;
+        };
+        let counter = &self.counters[index];
+        counter.count.fetch_add(1, Ordering::Relaxed);
+        counter
+            .wait_cycles
+            .fetch_add(wait_cycles, Ordering::Relaxed);
+    }
+
+    /// Returns the current values of the counters.
+    pub(super) fn snapshot(&self) -> GpuMetrics {
+        let commands = self
+            .counters
+            .iter()
+            .enumerate()
+            .filter_map(|(index, counter)| {
+                let count = counter.count.load(Ordering::Relaxed);
+                if count == 0 {
+                    return None;
+                }
+                let class = (index / COMMANDS_PER_CLASS + 1) as u32;
+                let cmd_type = class << 8 | (index % COMMANDS_PER_CLASS) as u32;
+                Some(CommandMetrics {
+                    cmd_type: VirtioGpuCtrlType::try_from(cmd_type).ok()?,
+                    count,
+                    total_wait: cycles_to_duration(counter.wait_cycles.load(Ordering::Relaxed)),
+                })
+            })
+            .collect();
+        GpuMetrics { commands }
+    }
+}
+
+/// A snapshot of the metrics of the commands sent to the device, returned by
+/// [`GPUDevice::metrics`].
+///
+/// [`GPUDevice::metrics`]: super::device::GPUDevice::metrics
+#[derive(Debug, Clone, Default)]
+pub struct GpuMetrics {
+    /// The metrics of each command type that has been sent at least once.
+    pub commands: Vec<CommandMetrics>,
+}
+
+impl GpuMetrics {
+    /// Returns the number of commands of `cmd_type` that have been sent.
+    pub fn count(&self, cmd_type: VirtioGpuCtrlType) -> u64 {
+        self.commands
+            .iter()
+            .find(|metrics| metrics.cmd_type == cmd_type)
+            .map_or(0, |metrics| metrics.count)
+    }
+}
+
+/// The metrics of a command type.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandMetrics {
+    /// The type of the commands.
+    pub cmd_type: VirtioGpuCtrlType,
+    /// The number of commands that have been sent.
+    pub count: u64,
+    /// The total time that the commands have waited for the device.
+    ///
+    /// Cursor commands and batched commands do not wait one by one, so their
+    /// time is not counted.
+    pub total_wait: Duration,
+}
+
+fn counter_index(cmd_type: u32) -> Option<usize> {
+    let class = (cmd_type >> 8) as usize;
+    let index = (cmd_type & 0xff) as usize;
+    if !(1..=NUM_CLASSES).contains(&class) || index >= COMMANDS_PER_CLASS {
+        return None;
+    }
+    Some((class - 1) * COMMANDS_PER_CLASS + index)
+}
+
+/// Returns the number of TSC cycles in `duration`, or 0 if the TSC
+/// frequency is unknown.
+pub(super) fn duration_to_cycles(duration: Duration) -> u64 {
+    let cycles = duration.as_nanos() * tsc_freq() as u128 / 1_000_000_000;
+    cycles.try_into().unwrap_or(u64::MAX)
+}
+
+pub(super) fn cycles_to_duration(cycles: u64) -> Duration {
+    let freq = tsc_freq();
+    if freq == 0 {
+        return Duration::ZERO;
+    }
+    let nanos = cycles as u128 * 1_000_000_000 / freq as u128;
+    Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn counts_by_command_type() {
+        let counters = CommandCounters::new();
+        counters.record(VirtioGpuCtrlType::CmdResourceFlush as u32, 10);
+        counters.record(VirtioGpuCtrlType::CmdResourceFlush as u32, 20);
+        counters.record(VirtioGpuCtrlType::CmdMoveCursor as u32, 0);
+        counters.record(VirtioGpuCtrlType::RespOkNodata as u32, 0);
+
+        let metrics = counters.snapshot();
+        assert_eq!(metrics.commands.len(), 2);
+        assert_eq!(metrics.count(VirtioGpuCtrlType::CmdResourceFlush), 2);
+        assert_eq!(metrics.count(VirtioGpuCtrlType::CmdMoveCursor), 1);
+        assert_eq!(metrics.count(VirtioGpuCtrlType::CmdGetDisplayInfo), 0);
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use ostd::sync::SpinLock;
+use spin::Once;
+
+use self::device::GPUDevice;
+
+pub mod batch;
+mod buffer;
+pub mod compositor;
+pub mod config;
+pub mod control;
+pub mod cursor;
+mod cursor_queue;
+#[cfg(feature = "gpu_demo")]
+mod demo;
+pub mod device;
+pub mod draw;
+pub mod edid;
+mod fence;
+pub mod header;
+pub mod metrics;
+pub mod pool;
+pub mod resource;
+pub mod rotation;
+mod software_cursor;
+
+pub static DEVICE_NAME: &str = "Virtio-GPU";
+
+pub fn register_device(name: String, device: Arc<GPUDevice>) {
+    GPU_DEVICE_TABLE
+        .get()
+        .unwrap()
+        .disable_irq()
+        .lock()
+        .insert(name, device);
+}
+
+pub fn get_device(str: &str) -> Option<Arc<GPUDevice>> {
+    let lock = GPU_DEVICE_TABLE.get().unwrap().disable_irq().lock();
+    lock.get(str).cloned()
+}
+
+pub fn all_devices() -> Vec<(String, Arc<GPUDevice>)> {
+    let gpu_devs = GPU_DEVICE_TABLE.get().unwrap().disable_irq().lock();
+    gpu_devs
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
+
+pub fn init() {
+    GPU_DEVICE_TABLE.call_once(|| SpinLock::new(BTreeMap::new()));
+}
+
+/// Returns the virtio-gpu device that the kernel has found, for the ktests.
+///
+/// The tests that send commands need a device, which the QEMU arguments in
+/// `tools/qemu_args.sh` attach. They are skipped without one, e.g., on a
+/// microvm.
+#[cfg(ktest)]
+fn test_device() -> Option<Arc<GPUDevice>> {
+    let device = GPU_DEVICE_TABLE.get().and_then(|_| get_device(DEVICE_NAME));
+    if device.is_none() {
+        log::warn!("no virtio-gpu device, skipping the test");
+    }
+    device
+}
+
+pub static GPU_DEVICE_TABLE: Once<SpinLock<BTreeMap<String, Arc<GPUDevice>>>> = Once::new();
+
+const QUEUE_CONTROL: u16 = 0;
+const QUEUE_CURSOR: u16 = 1;
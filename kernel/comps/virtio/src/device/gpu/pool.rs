@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! A pool of 2D resources for short-lived uses, such as transient blits.
+//!
+//! Creating a resource takes several round trips to the host, so released
+//! resources are kept with their guest backing storage attached and handed
+//! out again to the next request of the same size and format.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use log::warn;
+use ostd::{
+    mm::{DmaDirection, DmaStream},
+    sync::SpinLock,
+};
+
+use super::{
+    buffer::alloc_dma_stream,
+    control::VirtioGpuFormat,
+    device::{framebuffer_size, GPUDevice},
+};
+use crate::device::VirtioDeviceError;
+
+/// The maximum number of released resources kept by a pool.
+const MAX_FREE_RESOURCES: usize = 8;
+
+/// A pool of 2D resources with their guest backing storage attached.
+///
+/// The resources still in the pool are destroyed when the pool is dropped.
+pub struct ResourcePool {
+    device: Arc<GPUDevice>,
+    free: SpinLock<Vec<PooledResource>>,
+}
+
+/// A 2D resource acquired from a [`ResourcePool`].
+///
+/// Give it back with [`ResourcePool::release`], or it stays on the host.
+#[derive(Debug)]
+pub struct PooledResource {
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    format: VirtioGpuFormat,
+    backing: DmaStream,
+}
+
+impl PooledResource {
+    /// Returns the resource id.
+    pub fn resource_id(&self) -> u32 {
+        self.resource_id
+    }
+
+    /// Returns the guest backing storage, whose rows are packed.
+    pub fn backing(&self) -> &DmaStream {
+        &self.backing
+    }
+
+    fn matches(&self, width: u32, height: u32, format: VirtioGpuFormat) -> bool {
+        self.width == width && self.height == height && self.format == format
+    }
+}
+
+impl ResourcePool {
+    pub fn new(device: Arc<GPUDevice>) -> Self {
+        Self {
+            device,
+            free: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// Acquires a resource of the given size and format.
+    ///
+    /// A released resource is reused if one matches. Otherwise, a new one is
+    /// created on the host and its guest backing storage is attached.
+    pub fn acquire(
+        &self,
+        width: u32,
+        height: u32,
+        format: VirtioGpuFormat,
+    ) -> Result<PooledResource, VirtioDeviceError> {
+        {
+            let mut free = self.free.disable_irq().lock();
+            if let Some(index) = free
+                .iter()
+                .position(|resource| resource.matches(width, height, format))
+            {
+                return Ok(free.swap_remove(index));
+            }
+        }
+
+        self.device.check_resource_size(width, height)?;
+        let size = framebuffer_size(width, height, format)?;
+        let backing = alloc_dma_stream(size, DmaDirection::ToDevice)?;
+        let resource_id = self.device.alloc_resource_id();
+        self.device
+            .resource_create_2d(resource_id, format, width, height)?;
+        if let Err(err) = self.device.resource_attach_backing(resource_id, &backing) {
+            self.destroy(resource_id);
+            return Err(err);
+        }
+
+        Ok(PooledResource {
+            resource_id,
+            width,
+            height,
+            format,
+            backing,
+        })
+    }
+
+    /// Gives a resource acquired from the pool back to it.
+    ///
+    /// The resource is destroyed instead if the pool is full.
+    pub fn release(&self, resource: PooledResource) {
+        let mut free = self.free.disable_irq().lock();
+        if free.len() < MAX_FREE_RESOURCES {
+            free.push(resource);
+            return;
+        }
+        drop(free);
+
+        self.detach_and_destroy(resource.resource_id);
+    }
+
+    fn detach_and_destroy(&self, resource_id: u32) {
+        if let Err(err) = self.device.resource_detach_backing(resource_id) {
+            warn!(
+                "failed to detach the backing of pooled resource {}: {:?}",
+                resource_id, err
+            );
+        }
+        self.destroy(resource_id);
+    }
+
+    fn destroy(&self, resource_id: u32) {
+        if let Err(err) = self.device.resource_unref(resource_id) {
+            warn!(
+                "failed to release pooled resource {}: {:?}",
+                resource_id, err
+            );
+        }
+    }
+}
+
+impl Drop for ResourcePool {
+    fn drop(&mut self) {
+        let free = core::mem::take(&mut *self.free.disable_irq().lock());
+        for resource in free {
+            self.detach_and_destroy(resource.resource_id);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use ostd::mm::DmaStream;
+
+use super::control::{VirtioGpuFormat, VirtioGpuRect};
+
+/// The information of a 2D resource that the driver has created on the host.
+#[derive(Debug, Clone)]
+pub struct ResourceInfo {
+    width: u32,
+    height: u32,
+    format: VirtioGpuFormat,
+    /// The number of bytes between the starts of two rows in the backing storage.
+    stride: usize,
+    backing: Option<DmaStream>,
+    blob: bool,
+}
+
+impl ResourceInfo {
+    pub(super) fn new(width: u32, height: u32, format: VirtioGpuFormat, stride: usize) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            stride,
+            backing: None,
+            blob: false,
+        }
+    }
+
+    pub(super) fn new_blob(
+        width: u32,
+        height: u32,
+        format: VirtioGpuFormat,
+        backing: DmaStream,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            stride: packed_stride(width, format),
+            backing: Some(backing),
+            blob: true,
+        }
+    }
+
+    /// Returns the width of the resource, in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the resource, in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the pixel format of the resource.
+    pub fn format(&self) -> VirtioGpuFormat {
+        self.format
+    }
+
+    /// Returns the number of bytes between the starts of two rows in the
+    /// guest backing storage.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Returns whether the rows in the guest backing storage are packed,
+    /// i.e., have no padding between them.
+    ///
+    /// The host assumes packed rows when it copies more than one row at a time.
+    pub fn is_packed(&self) -> bool {
+        self.stride == packed_stride(self.width, self.format)
+    }
+
+    /// Returns the guest backing storage attached to the resource, if any.
+    pub fn backing(&self) -> Option<&DmaStream> {
+        self.backing.as_ref()
+    }
+
+    /// Returns whether the resource is a blob resource.
+    ///
+    /// The host uses the guest backing storage of a blob resource directly, so
+    /// its contents need no transfers to the host.
+    pub fn is_blob(&self) -> bool {
+        self.blob
+    }
+
+    /// Returns the number of bytes that the guest backing storage of the
+    /// resource needs, with the rows [`Self::stride`] bytes apart.
+    pub fn backing_size(&self) -> u64 {
+        if self.height == 0 {
+            return 0;
+        }
+        (self.height as u64 - 1) * self.stride as u64
+            + packed_stride(self.width, self.format) as u64
+    }
+
+    /// Returns the size of the resource as a rectangle at the origin.
+    pub fn rect(&self) -> VirtioGpuRect {
+        VirtioGpuRect::new(0, 0, self.width, self.height)
+    }
+
+    /// Returns whether `rect` lies within the resource.
+    pub fn contains(&self, rect: &VirtioGpuRect) -> bool {
+        rect.is_within(&self.rect())
+    }
+
+    pub(super) fn set_backing(&mut self, backing: Option<DmaStream>) {
+        self.backing = backing;
+    }
+}
+
+/// Returns the number of bytes of a row of `width` pixels of `format`.
+fn packed_stride(width: u32, format: VirtioGpuFormat) -> usize {
+    width as usize * format.bytes_per_pixel()
+}
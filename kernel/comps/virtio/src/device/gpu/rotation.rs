@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! The software rotation of the framebuffer.
+//!
+//! virtio-gpu cannot rotate a scanout, so a rotated framebuffer is drawn
+//! upright in its own backing storage and copied, rotated, to the backing
+//! storage of its host resource before each transfer to the host.
+
+use alloc::vec;
+
+use ostd::mm::{DmaStream, VmIo};
+
+use super::{control::VirtioGpuRect, draw::pixel_offset};
+
+/// The clockwise rotation of the framebuffer on the display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl Rotation {
+    /// Returns whether the rotation swaps the width and the height.
+    pub fn swaps_axes(self) -> bool {
+        matches!(self, Self::Deg90 | Self::Deg270)
+    }
+
+    /// Returns `rect` with its width and height swapped if the rotation
+    /// swaps the axes.
+    pub(super) fn rotate_size(self, rect: VirtioGpuRect) -> VirtioGpuRect {
+        if self.swaps_axes() {
+            VirtioGpuRect::new(rect.x(), rect.y(), rect.height(), rect.width())
+        } else {
+            rect
+        }
+    }
+
+    /// Returns where the `rect` area of an upright framebuffer of the size of
+    /// `bounds` ends up once the framebuffer is rotated.
+    pub(super) fn rotate_rect(self, rect: &VirtioGpuRect, bounds: &VirtioGpuRect) -> VirtioGpuRect {
+        let (x, y, width, height) = (rect.x(), rect.y(), rect.width(), rect.height());
+        let (bounds_width, bounds_height) = (bounds.width(), bounds.height());
+        match self {
+            Self::Deg0 => *rect,
+            Self::Deg90 => VirtioGpuRect::new(bounds_height - (y + height), x, height, width),
+            Self::Deg180 => VirtioGpuRect::new(
+                bounds_width - (x + width),
+                bounds_height - (y + height),
+                width,
+                height,
+            ),
+            Self::Deg270 => VirtioGpuRect::new(y, bounds_width - (x + width), height, width),
+        }
+    }
+
+    /// Copies the `rect` area of the upright framebuffer in `src` to its
+    /// rotated position in `dst`.
+    ///
+    /// The framebuffer has the size of `bounds`, whose offsets must not
+    /// overflow, and the rows of `src` and `dst` are `src_stride` and
+    /// `dst_stride` bytes apart, respectively.
+    #[expect(clippy::too_many_arguments)]
+    pub(super) fn copy_rotated(
+        self,
+        src: &DmaStream,
+        src_stride: usize,
+        dst: &DmaStream,
+        dst_stride: usize,
+        bounds: &VirtioGpuRect,
+        rect: &VirtioGpuRect,
+        bytes_per_pixel: usize,
+    ) {
+        let (width, height) = (rect.width() as usize, rect.height() as usize);
+        let row_len = width * bytes_per_pixel;
+        let mut pixels = vec![0u8; row_len * height];
+        for (row, pixels_row) in pixels.chunks_exact_mut(row_len).enumerate() {
+            let y = rect.y() + row as u32;
+            let offset = pixel_offset(rect.x(), y, src_stride, bytes_per_pixel).unwrap();
+            src.read_bytes(offset, pixels_row).unwrap();
+        }
+
+        let rotated = self.rotate_rect(rect, bounds);
+        let rotated_row_len = rotated.width() as usize * bytes_per_pixel;
+        let mut rotated_row = vec![0u8; rotated_row_len];
+        for row in 0..rotated.height() {
+            for (column, pixel) in rotated_row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                let (x, y) = self.source_of(column as u32, row, rect.width(), rect.height());
+                let src_offset = pixel_offset(x, y, row_len, bytes_per_pixel).unwrap();
+                pixel.copy_from_slice(&pixels[src_offset..src_offset + bytes_per_pixel]);
+            }
+            let offset =
+                pixel_offset(rotated.x(), rotated.y() + row, dst_stride, bytes_per_pixel).unwrap();
+            dst.write_bytes(offset, &rotated_row).unwrap();
+        }
+    }
+
+    /// Returns the position in an upright `width` by `height` area of the
+    /// pixel that ends up at `(x, y)` once the area is rotated.
+    fn source_of(self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Self::Deg0 => (x, y),
+            Self::Deg90 => (y, height - 1 - x),
+            Self::Deg180 => (width - 1 - x, height - 1 - y),
+            Self::Deg270 => (width - 1 - y, x),
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    const BOUNDS: VirtioGpuRect = VirtioGpuRect::new(0, 0, 640, 480);
+
+    #[ktest]
+    fn rotated_rects() {
+        let rect = VirtioGpuRect::new(10, 20, 30, 40);
+        assert_eq!(Rotation::Deg0.rotate_rect(&rect, &BOUNDS), rect);
+        assert_eq!(
+            Rotation::Deg90.rotate_rect(&rect, &BOUNDS),
+            VirtioGpuRect::new(420, 10, 40, 30)
+        );
+        assert_eq!(
+            Rotation::Deg180.rotate_rect(&rect, &BOUNDS),
+            VirtioGpuRect::new(600, 420, 30, 40)
+        );
+        assert_eq!(
+            Rotation::Deg270.rotate_rect(&rect, &BOUNDS),
+            VirtioGpuRect::new(20, 600, 40, 30)
+        );
+    }
+
+    #[ktest]
+    fn corners_follow_the_rotation() {
+        // The top-left pixel of a 3x2 area moves to the top-right corner by
+        // 90 degrees, the bottom-right one by 180, and the bottom-left one by 270.
+        assert_eq!(Rotation::Deg90.source_of(1, 0, 3, 2), (0, 0));
+        assert_eq!(Rotation::Deg180.source_of(2, 1, 3, 2), (0, 0));
+        assert_eq!(Rotation::Deg270.source_of(0, 2, 3, 2), (0, 0));
+        assert_eq!(Rotation::Deg90.source_of(0, 2, 3, 2), (2, 1));
+    }
+}
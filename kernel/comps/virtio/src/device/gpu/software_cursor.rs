@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! The software cursor, which the driver draws on the framebuffer when the
+//! device has no usable cursor queue.
+//!
+//! The cursor is never left in the guest backing storage of the framebuffer.
+//! It is composited over the pixels only while they are copied to the host,
+//! and the covered pixels are restored right afterward. So drawing never
+//! sees the cursor, and moving the cursor only needs its old and new areas to
+//! be flushed again.
+
+use alloc::{vec, vec::Vec};
+
+use ostd::mm::{DmaStream, VmIo};
+
+use super::{
+    control::VirtioGpuRect,
+    cursor::{CURSOR_FORMAT, CURSOR_SIZE},
+    draw::{clip_rect, pixel_offset},
+};
+
+/// The state of the software cursor.
+#[derive(Debug)]
+pub(super) struct SoftwareCursor {
+    /// The `CURSOR_SIZE * CURSOR_SIZE` image in [`CURSOR_FORMAT`], or `None`
+    /// if the cursor is hidden.
+    image: Option<Vec<u8>>,
+    hot_x: u32,
+    hot_y: u32,
+    x: u32,
+    y: u32,
+    /// Whether the cursor is hidden, regardless of its image.
+    hidden: bool,
+    /// The area where the host may show the cursor in a state other than the
+    /// current one, which must be flushed again.
+    stale: Option<VirtioGpuRect>,
+}
+
+/// The pixels of the framebuffer covered by the composited cursor.
+pub(super) struct CoveredPixels {
+    rect: VirtioGpuRect,
+    pixels: Vec<u8>,
+}
+
+impl SoftwareCursor {
+    pub(super) const fn new() -> Self {
+        Self {
+            image: None,
+            hot_x: 0,
+            hot_y: 0,
+            x: 0,
+            y: 0,
+            hidden: false,
+            stale: None,
+        }
+    }
+
+    /// Sets the cursor image, or hides the cursor if `image` is `None`.
+    pub(super) fn set_image(&mut self, image: Option<Vec<u8>>, hot_x: u32, hot_y: u32) {
+        self.mark_stale();
+        self.image = image;
+        self.hot_x = hot_x;
+        self.hot_y = hot_y;
+        self.mark_stale();
+    }
+
+    /// Moves the hot spot of the cursor to `(x, y)`.
+    pub(super) fn move_to(&mut self, x: u32, y: u32) {
+        self.mark_stale();
+        self.x = x;
+        self.y = y;
+        self.mark_stale();
+    }
+
+    /// Hides or shows the cursor, keeping its image and position.
+    pub(super) fn set_hidden(&mut self, hidden: bool) {
+        self.mark_stale();
+        self.hidden = hidden;
+        self.mark_stale();
+    }
+
+    /// Returns the area that must be flushed again to show the current cursor.
+    pub(super) fn stale(&self) -> Option<VirtioGpuRect> {
+        self.stale
+    }
+
+    /// Takes the area that must be flushed again, since the caller flushes it.
+    pub(super) fn take_stale(&mut self) -> Option<VirtioGpuRect> {
+        self.stale.take()
+    }
+
+    /// Records that `rect` is flushed without the cursor being composited.
+    pub(super) fn skip(&mut self, rect: &VirtioGpuRect) {
+        if let Some(area) = self.area() {
+            if clip_rect(rect, &area).is_some() {
+                self.mark_stale();
+            }
+        }
+    }
+
+    /// Composites the cursor over the `rect` area of a framebuffer, whose
+    /// pixels are in [`CURSOR_FORMAT`] and whose rows are `stride` bytes apart.
+    ///
+    /// Returns the covered pixels, which must be restored once the host has
+    /// copied the area, or `None` if the cursor is not shown in the area.
+    pub(super) fn composite(
+        &self,
+        backing: &DmaStream,
+        stride: usize,
+        bounds: &VirtioGpuRect,
+        rect: &VirtioGpuRect,
+    ) -> Option<CoveredPixels> {
+        let image = self.image.as_ref()?;
+        let covered = clip_rect(&clip_rect(&self.area()?, bounds)?, rect)?;
+
+        let bytes_per_pixel = CURSOR_FORMAT.bytes_per_pixel();
+        let row_len = covered.width() as usize * bytes_per_pixel;
+        let mut pixels = vec![0u8; row_len * covered.height() as usize];
+        let (left, top) = self.origin();
+        for (row, saved) in pixels.chunks_exact_mut(row_len).enumerate() {
+            let y = covered.y() + row as u32;
+            let offset = pixel_offset(covered.x(), y, stride, bytes_per_pixel).unwrap();
+            backing.read_bytes(offset, saved).unwrap();
+
+            let image_y = (y as i64 - top) as u32;
+            let image_x = (covered.x() as i64 - left) as u32;
+            let image_stride = CURSOR_SIZE as usize * bytes_per_pixel;
+            let image_offset =
+                pixel_offset(image_x, image_y, image_stride, bytes_per_pixel).unwrap();
+            let mut blended = saved.to_vec();
+            for (dst, src) in blended
+                .chunks_exact_mut(bytes_per_pixel)
+                .zip(image[image_offset..image_offset + row_len].chunks_exact(bytes_per_pixel))
+            {
+                blend(dst, src);
+            }
+            backing.write_bytes(offset, &blended).unwrap();
+        }
+
+        Some(CoveredPixels {
+            rect: covered,
+            pixels,
+        })
+    }
+
+    /// Returns the position of the top-left corner of the image, which may be
+    /// outside of the framebuffer.
+    fn origin(&self) -> (i64, i64) {
+        (
+            self.x as i64 - self.hot_x as i64,
+            self.y as i64 - self.hot_y as i64,
+        )
+    }
+
+    /// Returns the area covered by the image, without the parts at negative
+    /// coordinates, or `None` if the cursor is hidden.
+    fn area(&self) -> Option<VirtioGpuRect> {
+        if self.hidden {
+            return None;
+        }
+        self.image.as_ref()?;
+        let (left, top) = self.origin();
+        let (right, bottom) = (left + CURSOR_SIZE as i64, top + CURSOR_SIZE as i64);
+        let (x, y) = (left.max(0), top.max(0));
+        if right <= x || bottom <= y {
+            return None;
+        }
+        let (x_end, y_end) = (right.min(u32::MAX as i64), bottom.min(u32::MAX as i64));
+        Some(VirtioGpuRect::new(
+            x as u32,
+            y as u32,
+            (x_end - x) as u32,
+            (y_end - y) as u32,
+        ))
+    }
+
+    /// Adds the current area of the cursor to the stale area.
+    fn mark_stale(&mut self) {
+        let Some(area) = self.area() else {
+            return;
+        };
+        self.stale = Some(match self.stale {
+            Some(stale) => union_rect(&stale, &area),
+            None => area,
+        });
+    }
+}
+
+impl CoveredPixels {
+    /// Writes the covered pixels back to the framebuffer.
+    pub(super) fn restore(self, backing: &DmaStream, stride: usize) {
+        let bytes_per_pixel = CURSOR_FORMAT.bytes_per_pixel();
+        let row_len = self.rect.width() as usize * bytes_per_pixel;
+        for (row, saved) in self.pixels.chunks_exact(row_len).enumerate() {
+            let y = self.rect.y() + row as u32;
+            let offset = pixel_offset(self.rect.x(), y, stride, bytes_per_pixel).unwrap();
+            backing.write_bytes(offset, saved).unwrap();
+        }
+    }
+}
+
+/// Returns the smallest rectangle that contains both `a` and `b`.
+pub(super) fn union_rect(a: &VirtioGpuRect, b: &VirtioGpuRect) -> VirtioGpuRect {
+    let x = a.x().min(b.x());
+    let y = a.y().min(b.y());
+    let x_end = (a.x().saturating_add(a.width())).max(b.x().saturating_add(b.width()));
+    let y_end = (a.y().saturating_add(a.height())).max(b.y().saturating_add(b.height()));
+    VirtioGpuRect::new(x, y, x_end - x, y_end - y)
+}
+
+/// Blends a B8G8R8A8 source pixel over a destination pixel, keeping the
+/// alpha of the destination.
+fn blend(dst: &mut [u8], src: &[u8]) {
+    let alpha = src[3] as u32;
+    for channel in 0..3 {
+        let value = src[channel] as u32 * alpha + dst[channel] as u32 * (255 - alpha);
+        dst[channel] = ((value + 127) / 255) as u8;
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn union_of_rects() {
+        let a = VirtioGpuRect::new(10, 10, 20, 20);
+        let b = VirtioGpuRect::new(40, 0, 10, 10);
+        assert_eq!(union_rect(&a, &b), VirtioGpuRect::new(10, 0, 40, 30));
+        assert_eq!(union_rect(&a, &a), a);
+    }
+
+    #[ktest]
+    fn blending_keeps_translucency() {
+        let mut pixel = [0x00, 0x00, 0xff, 0xff];
+        blend(&mut pixel, &[0xff, 0xff, 0xff, 0x00]);
+        assert_eq!(pixel, [0x00, 0x00, 0xff, 0xff]);
+
+        blend(&mut pixel, &[0xff, 0x00, 0x00, 0x80]);
+        assert_eq!(pixel, [0x80, 0x00, 0x7f, 0xff]);
+
+        blend(&mut pixel, &[0x10, 0x20, 0x30, 0xff]);
+        assert_eq!(pixel, [0x10, 0x20, 0x30, 0xff]);
+    }
+
+    #[ktest]
+    fn moving_marks_old_and_new_areas_stale() {
+        let mut cursor = SoftwareCursor::new();
+        cursor.move_to(100, 100);
+        assert_eq!(cursor.take_stale(), None);
+
+        cursor.set_image(Some(vec![0; 4 * 64 * 64]), 10, 20);
+        assert_eq!(
+            cursor.take_stale(),
+            Some(VirtioGpuRect::new(90, 80, 64, 64))
+        );
+
+        cursor.move_to(5, 5);
+        assert_eq!(
+            cursor.take_stale(),
+            Some(VirtioGpuRect::new(0, 0, 154, 144))
+        );
+    }
+
+    #[ktest]
+    fn hidden_cursor_keeps_its_state() {
+        let mut cursor = SoftwareCursor::new();
+        cursor.set_image(Some(vec![0; 4 * 64 * 64]), 0, 0);
+        cursor.take_stale();
+
+        cursor.set_hidden(true);
+        assert_eq!(cursor.take_stale(), Some(VirtioGpuRect::new(0, 0, 64, 64)));
+        assert_eq!(cursor.area(), None);
+
+        cursor.move_to(100, 100);
+        assert_eq!(cursor.take_stale(), None);
+
+        cursor.set_hidden(false);
+        assert_eq!(
+            cursor.take_stale(),
+            Some(VirtioGpuRect::new(100, 100, 64, 64))
+        );
+    }
+}
@@ -6,6 +6,7 @@ use crate::queue::QueueError;
 
 pub mod block;
 pub mod console;
+pub mod gpu;
 pub mod input;
 pub mod network;
 pub mod socket;
@@ -45,12 +46,40 @@ pub enum VirtioDeviceError {
     QueuesAmountDoNotMatch(u16, u16),
     /// unknown error of queue
     QueueUnknownError,
+    /// The queue has no free descriptors for the request, which may be
+    /// retried after the device has used some buffers
+    QueueFull,
     /// The input virtio capability list contains invalid element
     CapabilityListError,
+    /// The device responded to a request with an unexpected type,
+    /// the element is the actual response type
+    ResponseError(u32),
+    /// The device has run out of host memory for the request
+    HostOutOfMemory,
+    /// The guest has run out of memory for the DMA buffers of the request
+    GuestOutOfMemory,
+    /// The requested scanout does not exist or is disabled,
+    /// the element is the scanout id
+    InvalidScanout(u32),
+    /// The feature required by the request is not negotiated with the device
+    FeatureNotSupported,
+    /// The requested resource has not been created on the device, or does not
+    /// have the size that the request requires, the element is the resource id
+    InvalidResource(u32),
+    /// The rectangle of the request is not within the resource
+    InvalidRect,
+    /// The device has not finished its initialization, has been reset, or is
+    /// being reset, while the request was made, so its virtqueues cannot be used
+    NotReady,
+    /// The device did not get into the awaited state before the timeout
+    Timeout,
 }
 
 impl From<QueueError> for VirtioDeviceError {
-    fn from(_: QueueError) -> Self {
-        VirtioDeviceError::QueueUnknownError
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::BufferTooSmall => VirtioDeviceError::QueueFull,
+            _ => VirtioDeviceError::QueueUnknownError,
+        }
     }
 }
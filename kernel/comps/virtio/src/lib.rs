@@ -17,6 +17,7 @@ use component::{init_component, ComponentInitError};
 use device::{
     block::device::BlockDevice,
     console::device::ConsoleDevice,
+    gpu::{self, device::GPUDevice},
     input::device::InputDevice,
     network::device::NetworkDevice,
     socket::{self, device::SocketDevice},
@@ -38,28 +39,9 @@ fn virtio_component_init() -> Result<(), ComponentInitError> {
     transport::init();
     // For vsock table static init
     socket::init();
+    gpu::init();
     while let Some(mut transport) = pop_device_transport() {
-        // Reset device
-        transport
-            .write_device_status(DeviceStatus::empty())
-            .unwrap();
-        while transport.read_device_status() != DeviceStatus::empty() {
-            spin_loop();
-        }
-
-        // Set to acknowledge
-        transport
-            .write_device_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER)
-            .unwrap();
-        // negotiate features
-        negotiate_features(&mut transport);
-
-        if !transport.is_legacy_version() {
-            // change to features ok status
-            let status =
-                DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK;
-            transport.write_device_status(status).unwrap();
-        }
+        reset_and_negotiate(&mut transport);
 
         let device_type = transport.device_type();
         let res = match transport.device_type() {
@@ -68,6 +50,7 @@ fn virtio_component_init() -> Result<(), ComponentInitError> {
             VirtioDeviceType::Network => NetworkDevice::init(transport),
             VirtioDeviceType::Console => ConsoleDevice::init(transport),
             VirtioDeviceType::Socket => SocketDevice::init(transport),
+            VirtioDeviceType::GPU => GPUDevice::init(transport).map(|_| ()),
             _ => {
                 warn!("[Virtio]: Found unimplemented device:{:?}", device_type);
                 Ok(())
@@ -93,6 +76,33 @@ fn pop_device_transport() -> Option<Box<dyn VirtioTransport>> {
     None
 }
 
+/// Resets the device and negotiates its features.
+///
+/// The device is left in the `FEATURES_OK` status, so the driver can set up
+/// the virtqueues and then call [`VirtioTransport::finish_init`].
+pub(crate) fn reset_and_negotiate(transport: &mut Box<dyn VirtioTransport>) {
+    // Reset device
+    transport
+        .write_device_status(DeviceStatus::empty())
+        .unwrap();
+    while transport.read_device_status() != DeviceStatus::empty() {
+        spin_loop();
+    }
+
+    // Set to acknowledge
+    transport
+        .write_device_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER)
+        .unwrap();
+    // negotiate features
+    negotiate_features(transport);
+
+    if !transport.is_legacy_version() {
+        // change to features ok status
+        let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK;
+        transport.write_device_status(status).unwrap();
+    }
+}
+
 fn negotiate_features(transport: &mut Box<dyn VirtioTransport>) {
     let features = transport.read_device_features();
     let mask = ((1u64 << 24) - 1) | (((1u64 << 24) - 1) << 50);
@@ -103,6 +113,7 @@ fn negotiate_features(transport: &mut Box<dyn VirtioTransport>) {
         VirtioDeviceType::Input => InputDevice::negotiate_features(device_specified_features),
         VirtioDeviceType::Console => ConsoleDevice::negotiate_features(device_specified_features),
         VirtioDeviceType::Socket => SocketDevice::negotiate_features(device_specified_features),
+        VirtioDeviceType::GPU => GPUDevice::negotiate_features(device_specified_features),
         _ => device_specified_features,
     };
     let mut support_feature = Feature::from_bits_truncate(features);
@@ -2,7 +2,7 @@
 
 //! Virtqueue
 
-use alloc::vec::Vec;
+use alloc::{vec, vec::Vec};
 use core::{
     mem::size_of,
     sync::atomic::{fence, Ordering},
@@ -11,7 +11,7 @@ use core::{
 use aster_rights::{Dup, TRightSet, TRights, Write};
 use aster_util::{field_ptr, safe_ptr::SafePtr};
 use bitflags::bitflags;
-use log::debug;
+use log::{debug, warn};
 use ostd::{
     mm::{DmaCoherent, FrameAllocOptions},
     offset_of, Pod,
@@ -54,6 +54,14 @@ pub struct VirtQueue {
     queue_size: u16,
     /// The number of used queues.
     num_used: u16,
+    /// The number of buffers that have been added but not popped yet.
+    num_outstanding: u16,
+    /// The number of descriptors in the chain of each outstanding buffer, by
+    /// the index of its head descriptor, or 0 if the buffer is not outstanding.
+    ///
+    /// This catches descriptors that leak in debug builds, e.g., because of a
+    /// bug in recycling.
+    chain_lens: Vec<u16>,
     /// The head desc index of the free list.
     free_head: u16,
     /// the index of the next avail ring index
@@ -174,6 +182,8 @@ impl VirtQueue {
             queue_size: size,
             queue_idx: idx as u32,
             num_used: 0,
+            num_outstanding: 0,
+            chain_lens: vec![0; size as usize],
             free_head: 0,
             avail_idx: 0,
             last_used_idx: 0,
@@ -230,6 +240,8 @@ impl VirtQueue {
                 .unwrap();
         }
         self.num_used += (inputs.len() + outputs.len()) as u16;
+        self.num_outstanding += 1;
+        self.chain_lens[head as usize] = (inputs.len() + outputs.len()) as u16;
 
         let avail_slot = self.avail_idx & (self.queue_size - 1);
 
@@ -266,10 +278,42 @@ impl VirtQueue {
         (self.queue_size - self.num_used) as usize
     }
 
+    /// The number of buffers that have been added but not popped yet.
+    ///
+    /// Once all buffers have been popped, this is 0 and all descriptors are free.
+    pub fn outstanding(&self) -> usize {
+        self.num_outstanding as usize
+    }
+
+    /// Recycles the descriptors of the popped buffer whose head is `head`.
+    fn retire(&mut self, head: u16) {
+        let num_recycled = self.recycle_descriptors(head);
+        self.num_outstanding = self.num_outstanding.saturating_sub(1);
+        let chain_len = core::mem::replace(&mut self.chain_lens[head as usize], 0);
+        if !cfg!(debug_assertions) {
+            return;
+        }
+
+        if chain_len != num_recycled {
+            warn!(
+                "virtqueue {}: buffer {} had {} descriptors, but {} are recycled",
+                self.queue_idx, head, chain_len, num_recycled
+            );
+        }
+        if self.num_outstanding == 0 && self.num_used != 0 {
+            warn!(
+                "virtqueue {}: {} descriptors leaked with no outstanding buffers",
+                self.queue_idx, self.num_used
+            );
+        }
+    }
+
     /// Recycle descriptors in the list specified by head.
     ///
     /// This will push all linked descriptors at the front of the free list.
-    fn recycle_descriptors(&mut self, mut head: u16) {
+    /// Returns the number of recycled descriptors.
+    fn recycle_descriptors(&mut self, mut head: u16) -> u16 {
+        let mut num_recycled = 0;
         let origin_free_head = self.free_head;
         self.free_head = head;
         loop {
@@ -282,6 +326,7 @@ impl VirtQueue {
                 .write_once(&(0u32))
                 .unwrap();
             self.num_used -= 1;
+            num_recycled += 1;
 
             let flags: DescFlags = field_ptr!(desc, Descriptor, flags).read_once().unwrap();
             if flags.contains(DescFlags::NEXT) {
@@ -296,6 +341,7 @@ impl VirtQueue {
                 break;
             }
         }
+        num_recycled
     }
 
     /// Get a token from device used buffers, return (token, len).
@@ -315,7 +361,7 @@ impl VirtQueue {
         let index = field_ptr!(&element_ptr, UsedElem, id).read_once().unwrap();
         let len = field_ptr!(&element_ptr, UsedElem, len).read_once().unwrap();
 
-        self.recycle_descriptors(index as u16);
+        self.retire(index as u16);
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
 
         Ok((index as u16, len))
@@ -343,7 +389,7 @@ impl VirtQueue {
             return Err(QueueError::WrongToken);
         }
 
-        self.recycle_descriptors(index as u16);
+        self.retire(index as u16);
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
 
         Ok(len)
@@ -1,6 +1,6 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::mem::size_of;
 
 use aster_rights::{ReadOp, WriteOp};
@@ -210,6 +210,11 @@ impl VirtioTransport for VirtioMmioTransport {
         None
     }
 
+    fn shared_memory_regions(&self) -> Vec<(u8, IoMem)> {
+        // TODO: Support shared memory regions through the `SHMSel` registers.
+        Vec::new()
+    }
+
     fn read_device_features(&self) -> u64 {
         // select low
         field_ptr!(&self.layout, VirtioMmioLayout, device_features_select)
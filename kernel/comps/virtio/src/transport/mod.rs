@@ -1,6 +1,6 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use core::fmt::Debug;
 
 use aster_util::safe_ptr::SafePtr;
@@ -63,6 +63,21 @@ pub trait VirtioTransport: Sync + Send + Debug {
     /// Get access to the device config BAR space.
     fn device_config_bar(&self) -> Option<(Bar, usize)>;
 
+    /// Get access to all shared memory regions of the device, with their ids.
+    ///
+    /// Shared memory regions are memory of the device that is mapped into the
+    /// guest address space.
+    fn shared_memory_regions(&self) -> Vec<(u8, IoMem)>;
+
+    /// Get access to the shared memory region with the given id.
+    ///
+    /// Returns `None` if the device has no such region.
+    fn shared_memory_region(&self, id: u8) -> Option<IoMem> {
+        self.shared_memory_regions()
+            .into_iter()
+            .find_map(|(region_id, io_mem)| (region_id == id).then_some(io_mem))
+    }
+
     // ====================Virtqueue related APIs====================
 
     /// Get the total number of queues
@@ -18,14 +18,18 @@ pub enum VirtioPciCpabilityType {
     IsrCfg = 3,
     DeviceCfg = 4,
     PciCfg = 5,
+    SharedMemoryCfg = 8,
 }
 
 #[derive(Debug, Clone)]
 pub struct VirtioPciCapabilityData {
     cfg_type: VirtioPciCpabilityType,
+    id: u8,
     offset: u32,
     length: u32,
     option: Option<u32>,
+    /// The high 32 bits of the length of a 64-bit capability.
+    length_hi: Option<u32>,
     memory_bar: Option<Arc<MemoryBar>>,
 }
 
@@ -46,6 +50,23 @@ impl VirtioPciCapabilityData {
         self.cfg_type.clone()
     }
 
+    /// Returns the id of the structure, which tells the shared memory regions apart.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Returns the offset of a 64-bit capability, such as a shared memory one.
+    ///
+    /// Its high 32 bits follow the common fields of the capability.
+    pub fn offset64(&self) -> u64 {
+        self.offset as u64 | ((self.option.unwrap_or(0) as u64) << 32)
+    }
+
+    /// Returns the length of a 64-bit capability, such as a shared memory one.
+    pub fn length64(&self) -> u64 {
+        self.length as u64 | ((self.length_hi.unwrap_or(0) as u64) << 32)
+    }
+
     pub fn option_value(&self) -> Option<u32> {
         self.option
     }
@@ -58,9 +79,11 @@ impl VirtioPciCapabilityData {
             3 => VirtioPciCpabilityType::IsrCfg,
             4 => VirtioPciCpabilityType::DeviceCfg,
             5 => VirtioPciCpabilityType::PciCfg,
+            8 => VirtioPciCpabilityType::SharedMemoryCfg,
             _ => panic!("Unsupported virtio capability type:{:?}", cfg_type),
         };
         let bar = vendor_cap.read8(4).unwrap();
+        let id = vendor_cap.read8(5).unwrap();
         let capability_length = vendor_cap.read8(2).unwrap();
         let offset = vendor_cap.read32(8).unwrap();
         let length = vendor_cap.read32(12).unwrap();
@@ -69,6 +92,11 @@ impl VirtioPciCapabilityData {
         } else {
             None
         };
+        let length_hi = if capability_length >= 0x18 {
+            Some(vendor_cap.read32(20).unwrap())
+        } else {
+            None
+        };
 
         let mut memory_bar = None;
         if let Some(bar) = bar_manager.bar(bar) {
@@ -83,9 +111,11 @@ impl VirtioPciCapabilityData {
         };
         Self {
             cfg_type,
+            id,
             offset,
             length,
             option,
+            length_hi,
             memory_bar,
         }
     }
@@ -1,6 +1,6 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use core::fmt::Debug;
 
 use aster_util::{field_ptr, safe_ptr::SafePtr};
@@ -57,6 +57,8 @@ pub struct VirtioPciModernTransport {
     common_device: PciCommonDevice,
     common_cfg: SafePtr<VirtioPciCommonCfg, IoMem>,
     device_cfg: VirtioPciCapabilityData,
+    /// The capabilities of the shared memory regions.
+    shared_memory_cfgs: Vec<VirtioPciCapabilityData>,
     notify: VirtioPciNotify,
     msix_manager: VirtioMsixManager,
 }
@@ -148,6 +150,27 @@ impl VirtioTransport for VirtioPciModernTransport {
         None
     }
 
+    fn shared_memory_regions(&self) -> Vec<(u8, IoMem)> {
+        self.shared_memory_cfgs
+            .iter()
+            .filter_map(|cfg| {
+                let offset = cfg.offset64() as usize;
+                let length = cfg.length64() as usize;
+                let bar_io_mem = cfg.memory_bar().as_ref()?.io_mem();
+                if length == 0 || offset.checked_add(length)? > bar_io_mem.length() {
+                    warn!(
+                        "Invalid shared memory region {}: offset {:#x}, length {:#x}",
+                        cfg.id(),
+                        offset,
+                        length
+                    );
+                    return None;
+                }
+                Some((cfg.id(), bar_io_mem.slice(offset..offset + length)))
+            })
+            .collect()
+    }
+
     fn read_device_features(&self) -> u64 {
         // select low
         field_ptr!(&self.common_cfg, VirtioPciCommonCfg, device_feature_select)
@@ -292,6 +315,7 @@ impl VirtioPciModernTransport {
         let mut notify = None;
         let mut common_cfg = None;
         let mut device_cfg = None;
+        let mut shared_memory_cfgs = Vec::new();
         for cap in common_device.capabilities().iter() {
             match cap.capability_data() {
                 CapabilityData::Vndr(vendor) => {
@@ -312,6 +336,9 @@ impl VirtioPciModernTransport {
                             device_cfg = Some(data);
                         }
                         VirtioPciCpabilityType::PciCfg => {}
+                        VirtioPciCpabilityType::SharedMemoryCfg => {
+                            shared_memory_cfgs.push(data);
+                        }
                     }
                 }
                 CapabilityData::Msix(data) => {
@@ -335,6 +362,7 @@ impl VirtioPciModernTransport {
             common_device,
             common_cfg,
             device_cfg,
+            shared_memory_cfgs,
             notify,
             msix_manager,
             device_type,
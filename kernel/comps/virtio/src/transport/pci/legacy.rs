@@ -1,6 +1,6 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 use core::fmt::Debug;
 
 use aster_util::safe_ptr::SafePtr;
@@ -215,6 +215,10 @@ impl VirtioTransport for VirtioPciLegacyTransport {
         Some((bar, base))
     }
 
+    fn shared_memory_regions(&self) -> Vec<(u8, IoMem)> {
+        Vec::new()
+    }
+
     fn read_device_features(&self) -> u64 {
         // Only Feature Bits 0 to 31 are accessible through the Legacy Interface.
         let features = self